@@ -1,12 +1,20 @@
-use std::{io::Write, net::TcpStream};
+use std::{
+    io::{self, Write},
+    process::exit,
+};
 
 use clap::{Parser, Subcommand};
-use kvs::Result;
+use kvs::{KvsClient, KvsError, Result};
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_BIN_NAME"), version = env!("CARGO_PKG_VERSION"), about = env!("CARGO_PKG_DESCRIPTION"), long_about = None)]
 struct Cli {
-    #[arg(long, default_value = "127.0.0.1:4000")]
+    #[arg(
+        long,
+        global = true,
+        env = "KVS_ADDR",
+        default_value = "127.0.0.1:4000"
+    )]
     addr: String,
     #[command(subcommand)]
     command: Command,
@@ -15,18 +23,87 @@ struct Cli {
 #[derive(Subcommand)]
 enum Command {
     /// Get the value for a key.
-    Get { key: String },
+    Get {
+        key: String,
+        /// Write the value verbatim, without a trailing newline; a missing key
+        /// writes nothing and exits with a non-zero code
+        #[arg(long)]
+        raw: bool,
+    },
     /// Remove given key from store, if it exists.
-    Rm { key: String },
+    Rm {
+        key: String,
+        /// Succeed even if the key does not exist
+        #[arg(long)]
+        if_exists: bool,
+    },
     /// Set a key to value.
     Set { key: String, value: String },
+    /// Set a key to value, only if the key does not exist.
+    SetNx { key: String, value: String },
+    /// Set a key to a new value, only if it currently holds the expected one.
+    Cas {
+        key: String,
+        expected: String,
+        new: String,
+    },
+    /// Remove a key, only if it currently holds the expected value.
+    RemoveIf { key: String, expected: String },
+    /// Print the server's metrics.
+    Metrics,
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
+    let mut client = KvsClient::connect(args.addr)?;
 
-    let mut stream = TcpStream::connect(args.addr)?;
+    let result = match args.command {
+        Command::Get { key, raw: true } => match client.get(key)? {
+            Some(value) => {
+                let mut stdout = io::stdout();
+                stdout.write_all(value.as_bytes())?;
+                stdout.flush()?;
+                Ok(())
+            }
+            None => exit(1),
+        },
+        Command::Get { key, raw: false } => client.get(key).map(|value| match value {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        }),
+        Command::Rm {
+            key,
+            if_exists: false,
+        } => client.remove(key),
+        Command::Rm {
+            key,
+            if_exists: true,
+        } => client.remove_if_exists(key).map(|_| ()),
+        Command::Set { key, value } => client.set(key, value),
+        Command::SetNx { key, value } => client.set_nx(key, value).map(condition),
+        Command::Cas { key, expected, new } => {
+            client.compare_and_swap(key, expected, new).map(condition)
+        }
+        Command::RemoveIf { key, expected } => client.remove_if(key, expected).map(condition),
+        Command::Metrics => client.metrics().map(|metrics| println!("{}", metrics)),
+    };
 
-    stream.write_all(&[1])?;
-    Ok(())
+    match result {
+        Err(
+            err
+            @ (KvsError::Server(_) | KvsError::Unsupported(_) | KvsError::UnexpectedResponse(_)),
+        ) => {
+            eprintln!("{}", err);
+            exit(1);
+        }
+        result => result,
+    }
+}
+
+/// Exits with a non-zero code if a conditional request's condition failed.
+fn condition(success: bool) {
+    if !success {
+        eprintln!("Condition not met");
+        exit(1);
+    }
 }
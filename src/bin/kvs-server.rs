@@ -1,20 +1,31 @@
 use std::{
     io,
     net::{SocketAddr, TcpListener},
+    path::{Path, PathBuf},
+    process::exit,
     str::FromStr,
+    sync::mpsc,
 };
 
 use clap::Parser;
-use kvs::{EngineType, KvServer, Result};
+use kvs::{
+    engine::{KvEngine, StoreError},
+    thread_pool::{SharedQueueThreadPool, ThreadPool},
+    EngineType, KvServer, Result,
+};
 use tracing::{event, Level};
 
 #[derive(Parser)]
 #[command(name = env!("CARGO_BIN_NAME"), version = env!("CARGO_PKG_VERSION"), about = env!("CARGO_PKG_DESCRIPTION"), long_about = None)]
 struct Cli {
-    #[arg(long, default_value = "127.0.0.1:4000")]
+    #[arg(long, env = "KVS_ADDR", default_value = "127.0.0.1:4000")]
     addr: String,
-    #[arg(long, default_value = "kvs")]
+    #[arg(long, env = "KVS_ENGINE", default_value = "kvs")]
     engine: EngineType,
+    /// Directory holding the store's data [default: $XDG_DATA_HOME/kvs if
+    /// set, otherwise the current directory]
+    #[arg(long, env = "KVS_DATA_DIR")]
+    data_dir: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
@@ -22,6 +33,10 @@ fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber)?;
 
     let args = Cli::parse();
+    let data_dir = match args.data_dir {
+        Some(dir) => dir,
+        None => default_data_dir()?,
+    };
     event!(
         name: "startup",
         target: "startup",
@@ -29,16 +44,52 @@ fn main() -> Result<()> {
         version = env!("CARGO_PKG_VERSION"),
         address = args.addr,
         engine = args.engine.to_string(),
+        data_dir = %data_dir.display(),
     );
 
     let address = SocketAddr::from_str(&args.addr)?;
     let listener = TcpListener::bind(address)?;
-    let mut server = KvServer::new();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get() as u32);
+    let server = KvServer::new(
+        open_engine(&args.engine, &data_dir)?,
+        SharedQueueThreadPool::new(threads)?,
+    );
 
-    // NOTE: Can't push this to CI; Unless you like long-running tests
-    // for stream in listener.incoming() {
-    //     server.handle_connection(stream?)?;
-    // }
+    let (shutdown, signal) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = shutdown.send(());
+    })
+    .map_err(io::Error::other)?;
+    server.run(listener, signal)
+}
+
+/// Returns the data directory used when none is configured: `kvs` under
+/// `XDG_DATA_HOME` if set, otherwise the current directory.
+fn default_data_dir() -> io::Result<PathBuf> {
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(data_home) if !data_home.is_empty() => Ok(PathBuf::from(data_home).join("kvs")),
+        _ => std::env::current_dir(),
+    }
+}
 
-    Ok(())
+/// Opens the engine in `dir`, creating the directory if needed.
+///
+/// Exits with an explanation if the directory is not writable, which would
+/// otherwise only surface as a bare permission error.
+fn open_engine(engine: &EngineType, dir: &Path) -> Result<Box<dyn KvEngine>> {
+    let res = std::fs::create_dir_all(dir)
+        .map_err(StoreError::from)
+        .and_then(|()| engine.open(dir));
+    match res {
+        Err(StoreError::Io(err)) if err.kind() == io::ErrorKind::PermissionDenied => {
+            eprintln!(
+                "Data directory {} is not writable: {}\n\
+                 Pass --data-dir, or set KVS_DATA_DIR or XDG_DATA_HOME, to store data elsewhere",
+                dir.display(),
+                err
+            );
+            exit(1);
+        }
+        res => Ok(res?),
+    }
 }
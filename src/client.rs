@@ -0,0 +1,191 @@
+//! Client for the key-value server
+//!
+//! A [`KvsClient`] keeps one connection to the server open and sends all of
+//! its requests over it.
+use std::{
+    io::{self, BufReader, ErrorKind},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+};
+
+use tracing::debug;
+
+use crate::{
+    protocol::{self, Request, Response},
+    KvsError, Result,
+};
+
+/// Client that reuses a single connection to a key-value server
+///
+/// If the server closes the connection between requests, for example because
+/// it was restarted, the client reconnects and resends the request once. A
+/// request the server may already have applied is only resent if applying it
+/// twice is harmless, as for gets and sets.
+pub struct KvsClient {
+    /// Addresses the server was resolved to, kept for reconnecting
+    addrs: Vec<SocketAddr>,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl KvsClient {
+    /// Connects to the server at `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+        let (reader, writer) = open(&addrs)?;
+        Ok(KvsClient {
+            addrs,
+            reader,
+            writer,
+        })
+    }
+
+    /// Gets the value of a key.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.request(&Request::Get { key })? {
+            Response::Value(value) => Ok(value),
+            response => Err(into_error(response)),
+        }
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        match self.request(&Request::Set { key, value })? {
+            Response::Ok => Ok(()),
+            response => Err(into_error(response)),
+        }
+    }
+
+    /// Removes a key.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::Server` is returned if the key does not exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        match self.request(&Request::Rm { key })? {
+            Response::Ok => Ok(()),
+            response => Err(into_error(response)),
+        }
+    }
+
+    /// Removes a key, treating a missing key as already removed.
+    ///
+    /// Returns whether the key existed.
+    pub fn remove_if_exists(&mut self, key: String) -> Result<bool> {
+        self.conditional(&Request::RmIfExists { key })
+    }
+
+    /// Sets the value of a key only if the key does not exist.
+    ///
+    /// Returns whether the value was set.
+    pub fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.conditional(&Request::SetNx { key, value })
+    }
+
+    /// Replaces the value of a key only if its current value equals
+    /// `expected`.
+    ///
+    /// Returns whether the value was replaced.
+    pub fn compare_and_swap(&mut self, key: String, expected: String, new: String) -> Result<bool> {
+        self.conditional(&Request::Cas { key, expected, new })
+    }
+
+    /// Removes a key only if its current value equals `expected`.
+    ///
+    /// Returns whether the key was removed.
+    pub fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.conditional(&Request::RemoveIf { key, expected })
+    }
+
+    /// Returns the server's metrics, as reported by the server.
+    pub fn metrics(&mut self) -> Result<String> {
+        match self.request(&Request::Metrics)? {
+            Response::Value(Some(metrics)) => Ok(metrics),
+            response => Err(into_error(response)),
+        }
+    }
+
+    /// Sends a request and returns the server's response as is.
+    ///
+    /// # Errors
+    ///
+    /// Returns an I/O error if the connection is closed before the server
+    /// answers a request that is not safe to resend, e.g. a conditional
+    /// write; the server may or may not have applied it.
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        match protocol::send(&self.writer, request) {
+            Ok(()) => {}
+            // The server never received the whole request, so it is safe to
+            // resend whatever it is.
+            Err(err) if is_disconnect(&err) => return self.resend(request),
+            Err(err) => return Err(err),
+        }
+        match protocol::receive(&mut self.reader) {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) if is_idempotent(request) => self.resend(request),
+            Err(err) if is_disconnect(&err) && is_idempotent(request) => self.resend(request),
+            Ok(None) => Err(closed_error()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reconnects to the server and sends the request again.
+    fn resend(&mut self, request: &Request) -> Result<Response> {
+        debug!(target: "client", "connection closed by server, reconnecting");
+        let (reader, writer) = open(&self.addrs)?;
+        self.reader = reader;
+        self.writer = writer;
+        protocol::send(&self.writer, request)?;
+        protocol::receive(&mut self.reader)?.ok_or_else(closed_error)
+    }
+
+    fn conditional(&mut self, request: &Request) -> Result<bool> {
+        match self.request(request)? {
+            Response::Success(success) => Ok(success),
+            response => Err(into_error(response)),
+        }
+    }
+}
+
+fn open(addrs: &[SocketAddr]) -> Result<(BufReader<TcpStream>, TcpStream)> {
+    let writer = TcpStream::connect(addrs)?;
+    let reader = BufReader::new(writer.try_clone()?);
+    Ok((reader, writer))
+}
+
+/// Whether an error means the server closed the connection before answering.
+fn is_disconnect(err: &KvsError) -> bool {
+    let kind = match err {
+        KvsError::Io(err) => err.kind(),
+        KvsError::Serde(err) => match err.io_error_kind() {
+            Some(kind) => kind,
+            None => return false,
+        },
+        _ => return false,
+    };
+    matches!(
+        kind,
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    )
+}
+
+/// Whether applying a request twice has the same effect as applying it once.
+fn is_idempotent(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::Get { .. } | Request::Set { .. } | Request::Metrics
+    )
+}
+
+/// Error for a connection closed before the server answered.
+fn closed_error() -> KvsError {
+    io::Error::new(ErrorKind::UnexpectedEof, "Connection closed by server").into()
+}
+
+/// Converts a response that does not answer the request into an error.
+fn into_error(response: Response) -> KvsError {
+    match response {
+        Response::Err(err) => KvsError::Server(err),
+        Response::Unsupported(capability) => KvsError::Unsupported(capability),
+        response => KvsError::UnexpectedResponse(response),
+    }
+}
@@ -0,0 +1,137 @@
+//! Bounded least-recently-used value cache
+//!
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// Caches values of recently read keys, bounded by the total size in bytes of
+/// the cached keys and values.
+///
+/// Once the capacity is exceeded the least recently used entries are evicted.
+/// A capacity of 0 disables the cache. Values are shared, so handing out a
+/// cached value does not copy it.
+#[derive(Debug, Default)]
+pub(crate) struct ValueCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<String, (Arc<str>, u64)>,
+    recency: BTreeMap<u64, String>,
+    /// Number of lookups served from the cache.
+    pub(crate) hits: u64,
+    /// Number of lookups that were not found in the cache.
+    pub(crate) misses: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to `capacity` bytes of keys and values.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the cached value for the key, marking it as recently used.
+    pub(crate) fn get(&mut self, key: &str) -> Option<Arc<str>> {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some((value, last_used)) => {
+                let key = self
+                    .recency
+                    .remove(last_used)
+                    .expect("cache entry missing from recency list");
+                *last_used = self.tick;
+                self.recency.insert(self.tick, key);
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches the value of a key, evicting least recently used entries if
+    /// needed. Values larger than the whole cache are not cached.
+    pub(crate) fn insert(&mut self, key: String, value: Arc<str>) {
+        self.remove(&key);
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+
+        while self.size + size > self.capacity {
+            let (_, oldest) = self
+                .recency
+                .pop_first()
+                .expect("cache size accounted for missing entries");
+            let (value, _) = self.entries.remove(&oldest).expect("missing cache entry");
+            self.size -= oldest.len() + value.len();
+        }
+
+        self.tick += 1;
+        self.size += size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// Returns whether the key is cached, without marking it as recently used
+    /// or counting the lookup.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Returns whether the cache is enabled.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Invalidates the cached value of a key.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some((value, last_used)) = self.entries.remove(key) {
+            self.recency.remove(&last_used);
+            self.size -= key.len() + value.len();
+        }
+    }
+
+    /// Invalidates all cached values.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ValueCache::new(12);
+        cache.insert("k1".to_owned(), Arc::from("v1"));
+        cache.insert("k2".to_owned(), Arc::from("v2"));
+        cache.insert("k3".to_owned(), Arc::from("v3"));
+        assert_eq!(cache.size, 12);
+
+        // k1 is now the most recently used; k2 should be evicted.
+        assert_eq!(cache.get("k1").as_deref(), Some("v1"));
+        cache.insert("k4".to_owned(), Arc::from("v4"));
+        assert_eq!(cache.get("k2"), None);
+        assert_eq!(cache.get("k1").as_deref(), Some("v1"));
+        assert_eq!(cache.get("k3").as_deref(), Some("v3"));
+        assert_eq!(cache.get("k4").as_deref(), Some("v4"));
+        assert_eq!(cache.size, 12);
+    }
+
+    #[test]
+    fn disabled_cache() {
+        let mut cache = ValueCache::new(0);
+        cache.insert("k1".to_owned(), Arc::from("v1"));
+        assert_eq!(cache.get("k1"), None);
+        assert_eq!(cache.size, 0);
+    }
+}
@@ -0,0 +1,646 @@
+//! On-disk encodings of log entries
+//!
+use super::{kvs::LogEntry, Result, StoreError};
+use std::{
+    io::{BufRead, Read},
+    ops::Range,
+};
+
+/// Bytes starting every binary fragment: a magic tag followed by the format
+/// version.
+///
+/// Version 2 added a checksum to every entry.
+pub(crate) const BINARY_HEADER: [u8; 5] = *b"KVSB\x02";
+
+/// Tag of a binary `LogEntry::Set` entry.
+const SET_TAG: u8 = 0;
+/// Tag of a binary `LogEntry::Rm` entry.
+const RM_TAG: u8 = 1;
+/// Tag of a binary `LogEntry::SetEx` entry.
+const SETEX_TAG: u8 = 2;
+/// Tag of a binary `LogEntry::SetBytes` entry.
+const SETBYTES_TAG: u8 = 3;
+/// Tag of a binary `LogEntry::SetCompressed` entry.
+const SETCOMPRESSED_TAG: u8 = 4;
+/// Flag set on the tag of binary entries outside the default namespace,
+/// whose namespace is written ahead of their key.
+const NAMESPACED_FLAG: u8 = 0x80;
+
+/// Encoding of the log entries written to fragments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Every entry is a JSON object.
+    ///
+    /// Fragments are human readable, but large and slow to parse; byte values
+    /// are written as arrays of numbers. Entries
+    /// carry no checksum, so corruption is only detected if it leaves invalid
+    /// JSON behind.
+    Json,
+    /// Every entry is a tag byte followed by the key and value, each prefixed
+    /// by its length as a little-endian `u32`. Expiring entries end with their
+    /// expiry time as a little-endian `u64`. Entries outside the default
+    /// namespace set the tag's high bit and carry their length-prefixed
+    /// namespace ahead of the key. Every entry is followed by the CRC32 of its
+    /// bytes, so corruption is detected when reading it.
+    ///
+    /// Fragments start with a header identifying the format, so fragments in
+    /// another encoding are rejected instead of misread.
+    #[default]
+    Binary,
+}
+
+impl Codec {
+    /// Returns the bytes written at the start of every fragment.
+    pub(crate) fn header(self) -> &'static [u8] {
+        match self {
+            Codec::Json => &[],
+            Codec::Binary => &BINARY_HEADER,
+        }
+    }
+
+    /// Guesses the codec of a fragment from its first bytes.
+    ///
+    /// Returns `None` if the bytes match no known encoding.
+    pub(crate) fn detect(start: &[u8]) -> Option<Codec> {
+        // Match the magic tag only, so any version is recognized as binary.
+        if start.starts_with(&BINARY_HEADER[..BINARY_HEADER.len() - 1]) {
+            Some(Codec::Binary)
+        } else if start.trim_ascii_start().starts_with(b"{") {
+            Some(Codec::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Serializes a log entry.
+    pub(crate) fn encode(self, entry: &LogEntry) -> Result<Vec<u8>> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(entry)?),
+            Codec::Binary => {
+                let (tag, ns, key) = match entry {
+                    LogEntry::Set { ns, key, .. } => (SET_TAG, ns, key),
+                    LogEntry::Rm { ns, key } => (RM_TAG, ns, key),
+                    LogEntry::SetEx { ns, key, .. } => (SETEX_TAG, ns, key),
+                    LogEntry::SetBytes { ns, key, .. } => (SETBYTES_TAG, ns, key),
+                    LogEntry::SetCompressed { ns, key, .. } => (SETCOMPRESSED_TAG, ns, key),
+                };
+                let mut buf = Vec::new();
+                if ns.is_empty() {
+                    buf.push(tag);
+                } else {
+                    buf.push(tag | NAMESPACED_FLAG);
+                    put_field(&mut buf, ns)?;
+                }
+                put_field(&mut buf, key)?;
+                match entry {
+                    LogEntry::Set { value, .. } => put_field(&mut buf, value)?,
+                    LogEntry::Rm { .. } => {}
+                    LogEntry::SetEx {
+                        value, expires_at, ..
+                    } => {
+                        put_field(&mut buf, value)?;
+                        buf.extend_from_slice(&expires_at.to_le_bytes());
+                    }
+                    LogEntry::SetBytes { value, .. } | LogEntry::SetCompressed { value, .. } => {
+                        put_field(&mut buf, value)?
+                    }
+                }
+                let checksum = crc32fast::hash(&buf);
+                buf.extend_from_slice(&checksum.to_le_bytes());
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Deserializes a single log entry, verifying its checksum if
+    /// `verify_checksum` is set.
+    pub(crate) fn decode(self, buf: &[u8], verify_checksum: bool) -> Result<LogEntry> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(buf)?),
+            Codec::Binary => read_binary(&mut &buf[..], buf.len(), verify_checksum)?
+                .map(|(entry, _)| entry)
+                .ok_or(StoreError::Fragment("empty log entry".into())),
+        }
+    }
+
+    /// Reads every entry of a fragment, from its start, passing each entry
+    /// and its byte range in the fragment to `f`.
+    ///
+    /// JSON entries may be separated by a single newline, which is included
+    /// in the range of the following entry.
+    ///
+    /// Returns the byte offset of a trailing entry cut short by the end of
+    /// the fragment, if any, e.g. because the process crashed while writing
+    /// it. The entries before it are passed to `f` as usual. A length prefix
+    /// corrupted to run past the end of the fragment looks the same; use
+    /// `is_torn_tail` to tell them apart.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreError::Fragment` if a binary fragment does not start with
+    /// the expected header, e.g. because it was written as JSON, or if JSON
+    /// entries are padded with anything else than a newline delimiter; such
+    /// padding would skew the positions of all following entries. Entries
+    /// larger than `max_entry_size` bytes are rejected the same way, without
+    /// reading more than `max_entry_size` bytes of them; binary entries are
+    /// rejected before their fields are allocated. So are binary entries not
+    /// matching their checksum.
+    pub(crate) fn read_entries(
+        self,
+        fragment: u64,
+        reader: &mut impl BufRead,
+        max_entry_size: usize,
+        mut f: impl FnMut(LogEntry, Range<u64>),
+    ) -> Result<Option<u64>> {
+        match self {
+            Codec::Json => {
+                let mut pos = 0;
+                loop {
+                    let (gap, end) = read_gap(reader)?;
+                    if !gap.is_empty() && gap != b"\n" {
+                        return Err(StoreError::Fragment(format!(
+                            "fragment {} has {} bytes of unexpected padding at byte offset {}",
+                            fragment,
+                            gap.len(),
+                            pos
+                        )));
+                    }
+                    if end {
+                        break;
+                    }
+
+                    // Reading one byte past the maximum entry size is enough
+                    // to tell an entry is too large, without allocating it.
+                    let limit = (max_entry_size.saturating_sub(gap.len()) as u64).saturating_add(1);
+                    let mut limited = (&mut *reader).take(limit);
+                    let mut de = serde_json::Deserializer::from_reader(&mut limited).into_iter();
+                    let (entry, len): (LogEntry, _) = match de.next() {
+                        Some(Ok(entry)) => (entry, de.byte_offset()),
+                        Some(Err(err)) if err.is_eof() && limited.limit() == 0 => {
+                            return Err(StoreError::Fragment(format!(
+                                "fragment {} at byte offset {}: entry exceeds the maximum entry size of {} bytes",
+                                fragment, pos, max_entry_size
+                            )));
+                        }
+                        Some(Err(err)) if err.is_eof() => return Ok(Some(pos)),
+                        Some(Err(err)) => return Err(err.into()),
+                        None => break,
+                    };
+                    let new_pos = pos + (gap.len() + len) as u64;
+                    if new_pos - pos > max_entry_size as u64 {
+                        return Err(StoreError::Fragment(format!(
+                            "fragment {} at byte offset {}: entry of {} bytes exceeds the maximum entry size of {} bytes",
+                            fragment,
+                            pos,
+                            new_pos - pos,
+                            max_entry_size
+                        )));
+                    }
+                    f(entry, pos..new_pos);
+                    pos = new_pos;
+                }
+            }
+            Codec::Binary => {
+                let mut header = Vec::with_capacity(BINARY_HEADER.len());
+                reader
+                    .by_ref()
+                    .take(BINARY_HEADER.len() as u64)
+                    .read_to_end(&mut header)?;
+                // A fragment created but never written to has no header yet.
+                if header.is_empty() {
+                    return Ok(None);
+                }
+                if header.len() < BINARY_HEADER.len() && BINARY_HEADER.starts_with(&header) {
+                    return Ok(Some(0));
+                }
+                if header[..] != BINARY_HEADER {
+                    if Codec::detect(&header) == Some(Codec::Binary) {
+                        return Err(StoreError::Fragment(format!(
+                            "fragment {} uses binary log format version {}, expected version {}",
+                            fragment,
+                            header[BINARY_HEADER.len() - 1],
+                            BINARY_HEADER[BINARY_HEADER.len() - 1]
+                        )));
+                    }
+                    return Err(StoreError::Fragment(format!(
+                        "fragment {} is not in the binary log format; was it written as JSON?",
+                        fragment
+                    )));
+                }
+
+                let mut pos = header.len() as u64;
+                loop {
+                    let (entry, size) = match read_binary(reader, max_entry_size, true) {
+                        Ok(Some(next)) => next,
+                        Ok(None) => break,
+                        Err(StoreError::Io(err))
+                            if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                        {
+                            return Ok(Some(pos))
+                        }
+                        Err(StoreError::Fragment(desc)) => {
+                            return Err(StoreError::Fragment(format!(
+                                "fragment {} at byte offset {}: {}",
+                                fragment, pos, desc
+                            )))
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    let new_pos = pos + size as u64;
+                    f(entry, pos..new_pos);
+                    pos = new_pos;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns whether the bytes following an entry cut short, as reported
+    /// by `read_entries`, can be a partially written final entry.
+    ///
+    /// A crash only ever cuts short the last entry, so its remains are no
+    /// larger than `max_entry_size` and hold no complete entry. Anything else
+    /// means the cut short entry is corrupt, e.g. its length prefix, and the
+    /// entries following it must not be discarded along with it.
+    pub(crate) fn is_torn_tail(self, tail: &[u8], max_entry_size: usize) -> bool {
+        if tail.len() > max_entry_size {
+            return false;
+        }
+        !(1..tail.len()).any(|start| {
+            let buf = &tail[start..];
+            match self {
+                Codec::Json => {
+                    buf[0] == b'{'
+                        && matches!(
+                            serde_json::Deserializer::from_slice(buf)
+                                .into_iter::<LogEntry>()
+                                .next(),
+                            Some(Ok(_))
+                        )
+                }
+                Codec::Binary => matches!(read_binary(&mut &buf[..], buf.len(), true), Ok(Some(_))),
+            }
+        })
+    }
+}
+
+/// Consumes the whitespace preceding the next JSON entry.
+///
+/// Returns the whitespace and whether the end of the stream was reached.
+fn read_gap(reader: &mut impl BufRead) -> Result<(Vec<u8>, bool)> {
+    let mut gap = Vec::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok((gap, true));
+        }
+        let len = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        gap.extend_from_slice(&buf[..len]);
+        let found_entry = len < buf.len();
+        reader.consume(len);
+        if found_entry {
+            return Ok((gap, false));
+        }
+    }
+}
+
+/// Appends a length-prefixed field to a binary entry.
+fn put_field(buf: &mut Vec<u8>, field: impl AsRef<[u8]>) -> Result<()> {
+    let field = field.as_ref();
+    let len = u32::try_from(field.len())
+        .map_err(|_| StoreError::Fragment(format!("entry field of {} bytes", field.len())))?;
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(field);
+    Ok(())
+}
+
+/// Reads a length-prefixed UTF-8 field of a binary entry, refusing to
+/// allocate fields longer than `max` bytes.
+fn read_field(reader: &mut impl Read, max: usize) -> Result<String> {
+    Ok(String::from_utf8(read_bytes_field(reader, max)?)?)
+}
+
+/// Reads a length-prefixed field of a binary entry, refusing to allocate
+/// fields longer than `max` bytes.
+fn read_bytes_field(reader: &mut impl Read, max: usize) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > max {
+        return Err(StoreError::Fragment(format!(
+            "entry field of {} bytes exceeds the remaining {} bytes of the maximum entry size",
+            len, max
+        )));
+    }
+    let mut field = vec![0; len];
+    reader.read_exact(&mut field)?;
+    Ok(field)
+}
+
+/// Feeds the bytes read from a reader into a checksum.
+struct ChecksumReader<'a, R> {
+    reader: &'a mut R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Read for ChecksumReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Reads the next binary entry, returning it along with its size in bytes.
+///
+/// Returns `None` at the end of the stream. Entries declaring more than `max`
+/// bytes and, if `verify_checksum` is set, entries not matching their
+/// checksum are rejected with `StoreError::Fragment`.
+fn read_binary(
+    reader: &mut impl Read,
+    max: usize,
+    verify_checksum: bool,
+) -> Result<Option<(LogEntry, usize)>> {
+    let mut tag = [0; 1];
+    if reader.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&tag);
+    let mut checksummed = ChecksumReader { reader, hasher };
+    let reader = &mut checksummed;
+    let (ns, ns_size) = if tag[0] & NAMESPACED_FLAG != 0 {
+        let ns = read_field(reader, max)?;
+        let size = 4 + ns.len();
+        (ns, size)
+    } else {
+        (String::new(), 0)
+    };
+    let max_fields = max.saturating_sub(ns_size);
+    let key = read_field(reader, max_fields)?;
+    let (entry, size) = match tag[0] & !NAMESPACED_FLAG {
+        SET_TAG => {
+            let value = read_field(reader, max_fields - key.len())?;
+            let size = 1 + 8 + key.len() + value.len();
+            (LogEntry::Set { ns, key, value }, size)
+        }
+        RM_TAG => {
+            let size = 1 + 4 + key.len();
+            (LogEntry::Rm { ns, key }, size)
+        }
+        SETEX_TAG => {
+            let value = read_field(reader, max_fields - key.len())?;
+            let mut expires_at = [0; 8];
+            reader.read_exact(&mut expires_at)?;
+            let size = 1 + 16 + key.len() + value.len();
+            let entry = LogEntry::SetEx {
+                ns,
+                key,
+                value,
+                expires_at: u64::from_le_bytes(expires_at),
+            };
+            (entry, size)
+        }
+        SETBYTES_TAG => {
+            let value = read_bytes_field(reader, max_fields - key.len())?;
+            let size = 1 + 8 + key.len() + value.len();
+            (LogEntry::SetBytes { ns, key, value }, size)
+        }
+        SETCOMPRESSED_TAG => {
+            let value = read_bytes_field(reader, max_fields - key.len())?;
+            let size = 1 + 8 + key.len() + value.len();
+            (LogEntry::SetCompressed { ns, key, value }, size)
+        }
+        tag => {
+            return Err(StoreError::Fragment(format!(
+                "unknown log entry tag {}",
+                tag
+            )))
+        }
+    };
+    let size = ns_size + size + 4;
+    if size > max {
+        return Err(StoreError::Fragment(format!(
+            "entry of {} bytes exceeds the maximum entry size of {} bytes",
+            size, max
+        )));
+    }
+
+    let mut checksum = [0; 4];
+    checksummed.reader.read_exact(&mut checksum)?;
+    let expected = checksummed.hasher.finalize();
+    if verify_checksum && u32::from_le_bytes(checksum) != expected {
+        return Err(StoreError::Fragment(format!(
+            "checksum mismatch: entry has {:#010x}, expected {:#010x}",
+            u32::from_le_bytes(checksum),
+            expected
+        )));
+    }
+    Ok(Some((entry, size)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Entries should decode to what was encoded, at the expected offsets.
+    #[test]
+    fn binary_round_trip() -> Result<()> {
+        let entries = vec![
+            LogEntry::Set {
+                ns: String::new(),
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            LogEntry::Rm {
+                ns: String::new(),
+                key: "key1".to_owned(),
+            },
+            LogEntry::Set {
+                ns: String::new(),
+                key: "".to_owned(),
+                value: "\n \u{1F980}".to_owned(),
+            },
+            LogEntry::SetEx {
+                ns: String::new(),
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+                expires_at: 1_700_000_000_000,
+            },
+            LogEntry::SetBytes {
+                ns: String::new(),
+                key: "key3".to_owned(),
+                value: vec![0, 0xff, 0xc3, 0x28, 0],
+            },
+            LogEntry::SetCompressed {
+                ns: String::new(),
+                key: "key4".to_owned(),
+                value: vec![0x28, 0xb5, 0x2f, 0xfd],
+            },
+            LogEntry::Set {
+                ns: "ns1".to_owned(),
+                key: "key1".to_owned(),
+                value: "value5".to_owned(),
+            },
+            LogEntry::Rm {
+                ns: "ns1".to_owned(),
+                key: "key1".to_owned(),
+            },
+        ];
+
+        let mut fragment = Codec::Binary.header().to_vec();
+        let mut ranges = Vec::new();
+        for entry in &entries {
+            let buf = Codec::Binary.encode(entry)?;
+            let start = fragment.len() as u64;
+            fragment.extend_from_slice(&buf);
+            ranges.push(start..fragment.len() as u64);
+            assert_eq!(
+                format!("{:?}", Codec::Binary.decode(&buf, true)?),
+                format!("{:?}", entry)
+            );
+        }
+
+        let mut read = Vec::new();
+        Codec::Binary.read_entries(0, &mut &fragment[..], usize::MAX, |entry, range| {
+            read.push((format!("{:?}", entry), range))
+        })?;
+        let expected: Vec<_> = entries
+            .iter()
+            .map(|entry| format!("{:?}", entry))
+            .zip(ranges)
+            .collect();
+        assert_eq!(read, expected);
+        Ok(())
+    }
+
+    // Newline delimited JSON entries should be read at their offsets, while
+    // any other padding should be rejected.
+    #[test]
+    fn json_padding() -> Result<()> {
+        let entry = LogEntry::Rm {
+            ns: String::new(),
+            key: "key1".to_owned(),
+        };
+        let buf = Codec::Json.encode(&entry)?;
+        let len = buf.len() as u64;
+
+        let delimited = [&buf[..], b"\n", &buf[..], b"\n"].concat();
+        let mut ranges = Vec::new();
+        Codec::Json.read_entries(0, &mut &delimited[..], usize::MAX, |_, range| {
+            ranges.push(range)
+        })?;
+        assert_eq!(ranges, vec![0..len, len..2 * len + 1]);
+
+        let padded = [&buf[..], b" ", &buf[..]].concat();
+        let res = Codec::Json.read_entries(0, &mut &padded[..], usize::MAX, |_, _| {});
+        assert!(matches!(res, Err(StoreError::Fragment(msg)) if msg.contains(&len.to_string())));
+        Ok(())
+    }
+
+    // An entry cut short by the end of the fragment should be reported, while
+    // the entries before it are still read.
+    #[test]
+    fn truncated_tail() -> Result<()> {
+        let entry = LogEntry::Set {
+            ns: String::new(),
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        };
+        for codec in [Codec::Json, Codec::Binary] {
+            let buf = codec.encode(&entry)?;
+            let mut fragment = codec.header().to_vec();
+            fragment.extend_from_slice(&buf);
+            let end = fragment.len() as u64;
+            fragment.extend_from_slice(&buf[..buf.len() / 2]);
+
+            let mut read = 0;
+            let truncated =
+                codec.read_entries(0, &mut &fragment[..], usize::MAX, |_, _| read += 1)?;
+            assert_eq!((read, truncated), (1, Some(end)), "{:?}", codec);
+
+            let tail = &fragment[end as usize..];
+            assert!(codec.is_torn_tail(tail, usize::MAX), "{:?}", codec);
+            assert!(!codec.is_torn_tail(tail, tail.len() - 1), "{:?}", codec);
+            let corrupt = [tail, &buf[..]].concat();
+            assert!(!codec.is_torn_tail(&corrupt, usize::MAX), "{:?}", codec);
+        }
+
+        let truncated =
+            Codec::Binary.read_entries(0, &mut &BINARY_HEADER[..2], usize::MAX, |_, _| {})?;
+        assert_eq!(truncated, Some(0));
+        Ok(())
+    }
+
+    // JSON fragments should be rejected by the binary codec.
+    #[test]
+    fn binary_rejects_json() -> Result<()> {
+        let entry = LogEntry::Rm {
+            ns: String::new(),
+            key: "key1".to_owned(),
+        };
+        let fragment = Codec::Json.encode(&entry)?;
+        let res = Codec::Binary.read_entries(3, &mut &fragment[..], usize::MAX, |_, _| {});
+        assert!(matches!(res, Err(StoreError::Fragment(msg)) if msg.contains("fragment 3")));
+        Ok(())
+    }
+
+    // Entries of the default namespace should be encoded as before namespaces
+    // existed, while other entries keep their namespace.
+    #[test]
+    fn namespaces() -> Result<()> {
+        let entry = LogEntry::Set {
+            ns: String::new(),
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        };
+        assert_eq!(
+            Codec::Json.encode(&entry)?,
+            br#"{"Set":{"key":"key1","value":"value1"}}"#
+        );
+        assert_eq!(Codec::Binary.encode(&entry)?[0], SET_TAG);
+
+        let entry = LogEntry::Set {
+            ns: "ns1".to_owned(),
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        };
+        for codec in [Codec::Json, Codec::Binary] {
+            let decoded = codec.decode(&codec.encode(&entry)?, true)?;
+            assert!(
+                matches!(decoded, LogEntry::Set { ref ns, .. } if ns == "ns1"),
+                "{:?}",
+                codec
+            );
+        }
+        Ok(())
+    }
+
+    // Oversized JSON entries should be rejected after reading at most the
+    // maximum entry size, even if they never end.
+    #[test]
+    fn json_entry_size() -> Result<()> {
+        let value = "v".repeat(1_000);
+        let entry = LogEntry::Set {
+            ns: String::new(),
+            key: "key1".to_owned(),
+            value: value.clone(),
+        };
+        let buf = Codec::Json.encode(&entry)?;
+        let unterminated = format!(r#"{{"Set":{{"key":"key1","value":"{}"#, value);
+        for fragment in [&buf[..], unterminated.as_bytes()] {
+            let res = Codec::Json.read_entries(0, &mut &fragment[..], 100, |_, _| {});
+            assert!(
+                matches!(res, Err(StoreError::Fragment(ref msg)) if msg.contains("maximum entry size")),
+                "{:?}",
+                res
+            );
+        }
+
+        let mut read = 0;
+        Codec::Json.read_entries(0, &mut &buf[..], buf.len(), |_, _| read += 1)?;
+        assert_eq!(read, 1);
+        Ok(())
+    }
+}
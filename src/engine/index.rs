@@ -0,0 +1,121 @@
+//! In-memory index of the live entries of a store
+//!
+use std::collections::BTreeMap;
+
+use super::kvs::EntryPosition;
+
+/// Namespace holding the keys written directly to a store, rather than
+/// through a namespace handle.
+pub(crate) const DEFAULT_NAMESPACE: &str = "";
+
+/// Keys of a namespace without any.
+static NO_KEYS: BTreeMap<String, EntryPosition> = BTreeMap::new();
+
+/// Locates the current entry of every key, by namespace and key.
+///
+/// Every namespace has its own keys, so the same key in two namespaces
+/// refers to two unrelated entries. Namespaces are dropped once their last
+/// key is removed.
+#[derive(Debug, Default)]
+pub(crate) struct Index {
+    namespaces: BTreeMap<String, BTreeMap<String, EntryPosition>>,
+}
+
+impl Index {
+    /// Returns the keys of a namespace along with their entries.
+    pub(crate) fn namespace(&self, ns: &str) -> &BTreeMap<String, EntryPosition> {
+        self.namespaces.get(ns).unwrap_or(&NO_KEYS)
+    }
+
+    /// Returns the entry of a key.
+    pub(crate) fn get(&self, ns: &str, key: &str) -> Option<&EntryPosition> {
+        self.namespaces.get(ns)?.get(key)
+    }
+
+    /// Returns the entry of a key for updating it in place.
+    pub(crate) fn get_mut(&mut self, ns: &str, key: &str) -> Option<&mut EntryPosition> {
+        self.namespaces.get_mut(ns)?.get_mut(key)
+    }
+
+    /// Points a key at a new entry, returning its previous entry.
+    pub(crate) fn insert(
+        &mut self,
+        ns: &str,
+        key: String,
+        ep: EntryPosition,
+    ) -> Option<EntryPosition> {
+        match self.namespaces.get_mut(ns) {
+            Some(keys) => keys.insert(key, ep),
+            None => {
+                self.namespaces
+                    .insert(ns.to_owned(), BTreeMap::from([(key, ep)]));
+                None
+            }
+        }
+    }
+
+    /// Removes a key, returning its entry.
+    pub(crate) fn remove(&mut self, ns: &str, key: &str) -> Option<EntryPosition> {
+        let keys = self.namespaces.get_mut(ns)?;
+        let ep = keys.remove(key);
+        if keys.is_empty() {
+            self.namespaces.remove(ns);
+        }
+        ep
+    }
+
+    /// Returns the number of keys in all namespaces.
+    pub(crate) fn len(&self) -> usize {
+        self.namespaces.values().map(BTreeMap::len).sum()
+    }
+
+    /// Returns every key of every namespace along with its entry, sorted by
+    /// namespace and key.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &String, &EntryPosition)> {
+        self.namespaces
+            .iter()
+            .flat_map(|(ns, keys)| keys.iter().map(move |(key, ep)| (ns.as_str(), key, ep)))
+    }
+
+    /// Like `iter`, with mutable entries.
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &String, &mut EntryPosition)> {
+        self.namespaces
+            .iter_mut()
+            .flat_map(|(ns, keys)| keys.iter_mut().map(move |(key, ep)| (ns.as_str(), key, ep)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(pos: u64) -> EntryPosition {
+        (0, pos..pos + 1, 0).into()
+    }
+
+    // The same key in two namespaces should have independent entries.
+    #[test]
+    fn namespaces_are_isolated() {
+        let mut index = Index::default();
+        assert!(index
+            .insert(DEFAULT_NAMESPACE, "key1".to_owned(), entry(0))
+            .is_none());
+        assert!(index.insert("ns1", "key1".to_owned(), entry(1)).is_none());
+        assert_eq!(index.get(DEFAULT_NAMESPACE, "key1").unwrap().pos, 0);
+        assert_eq!(index.get("ns1", "key1").unwrap().pos, 1);
+        assert!(index.get("ns2", "key1").is_none());
+        assert_eq!(index.len(), 2);
+
+        assert_eq!(index.remove("ns1", "key1").unwrap().pos, 1);
+        assert!(index.get("ns1", "key1").is_none());
+        assert_eq!(index.get(DEFAULT_NAMESPACE, "key1").unwrap().pos, 0);
+        assert!(index.namespace("ns1").is_empty());
+        assert_eq!(
+            index
+                .iter()
+                .map(|(ns, key, _)| (ns, key.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(DEFAULT_NAMESPACE, "key1")]
+        );
+    }
+}
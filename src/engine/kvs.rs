@@ -1,370 +1,5183 @@
 //! Built-in storage Key-Value Database Engine
 //!
-use super::{KvEngine, Result, StoreError};
+use super::{
+    cache::ValueCache,
+    codec::{Codec, BINARY_HEADER},
+    index::{Index, DEFAULT_NAMESPACE},
+    EngineCapabilities, EngineStats, KvEngine, Result, StoreError,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap},
     fs::{File, OpenOptions},
+    hash::{DefaultHasher, Hasher},
     io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    ops::Range,
+    ops::{Bound, Range},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        TryLockError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tracing::{debug, debug_span, info, span::EnteredSpan, warn};
+
+/// File extension for logs
+pub const LOG_EXTENSION: &str = "kv";
+
+/// Subdirectory of the store holding fragments replaced by a compaction
+pub const TRASH_DIR: &str = ".trash";
+
+/// File recording the generation below which fragments were replaced by a
+/// compaction but could not be removed; it only exists while any are left.
+const REPLACED_FILE: &str = "replaced";
+
+/// Default byte threshold of unclaimed space that should trigger compaction
+///
+/// Default: 1MB
+const COMPACTION_THRESHOLD: usize = 1_000_000;
+
+/// Default size in bytes of the smallest value that is compressed
+///
+/// Default: 1KB
+const MIN_COMPRESS_BYTES: usize = 1_000;
+
+/// Default size in bytes of the largest log entry accepted
+///
+/// Default: 64MB
+const MAX_ENTRY_SIZE: usize = 64_000_000;
+
+/// A list specifying supported Write-Ahead Log(WAL) entries.
+///
+/// Every entry belongs to a namespace, `ns`; entries of the default namespace
+/// leave it out of their encoding, so they read and write as they did before
+/// namespaces existed.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum LogEntry {
+    Set {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+        value: String,
+    },
+    Rm {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+    },
+    /// Sets a value that expires at the given unix time, in milliseconds.
+    SetEx {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+        value: String,
+        expires_at: u64,
+    },
+    /// Sets a value holding arbitrary bytes, which need not be UTF-8.
+    SetBytes {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+        value: Vec<u8>,
+    },
+    /// Sets a value compressed with zstd, written by `set` or `set_bytes`.
+    SetCompressed {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        ns: String,
+        key: String,
+        value: Vec<u8>,
+    },
+}
+
+/// Keys whose values could not be read, each with the error reading it
+/// failed with.
+pub type KeyErrors = Vec<(String, StoreError)>;
+
+/// Represents the location of an entry in the log fragments.
+#[derive(Debug, Clone)]
+pub struct EntryPosition {
+    /// Fragment the entry is currently located in.
+    pub fragment: u64,
+    /// Position of the entry in the fragment
+    pub pos: u64,
+    /// Size of the entry
+    pub size: usize,
+    /// Hash of the entry's value
+    pub value_hash: u64,
+    /// Unix time, in milliseconds, at which the entry expires, if ever
+    pub expires_at: Option<u64>,
+}
+
+impl EntryPosition {
+    /// Returns whether the entry has expired at the given unix time, in
+    /// milliseconds.
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl From<(u64, Range<u64>, u64)> for EntryPosition {
+    fn from(value: (u64, Range<u64>, u64)) -> Self {
+        Self {
+            fragment: value.0,
+            pos: value.1.start,
+            size: (value.1.end - value.1.start) as usize,
+            value_hash: value.2,
+            expires_at: None,
+        }
+    }
+}
+
+/// Options used when opening a [`KvStore`].
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    /// Generation of the first fragment created for a fresh store.
+    ///
+    /// Has no effect on a store that already contains fragments. Opening an
+    /// existing store whose fragments predate this generation fails.
+    ///
+    /// Default: 0
+    pub initial_fragment: u64,
+    /// Capacity in bytes of the in-memory cache holding recently read values.
+    ///
+    /// Default: 0 (disabled)
+    pub value_cache_capacity: usize,
+    /// How long fragments replaced by a compaction are retained.
+    ///
+    /// When set, compaction moves replaced fragments into the [`TRASH_DIR`]
+    /// subdirectory instead of deleting them. Trashed fragments older than the
+    /// retention period are deleted by the next compaction.
+    ///
+    /// Default: `None` (delete immediately)
+    pub trash_retention: Option<Duration>,
+    /// Write entries in key order during compaction.
+    ///
+    /// Keys sharing a prefix end up next to each other in the compacted
+    /// fragment, so prefix scans read sequentially. Sorting makes compaction
+    /// slower.
+    ///
+    /// Default: false
+    pub ordered_compaction: bool,
+    /// Skip writing a value identical to the key's current value.
+    ///
+    /// Values are compared using the hash kept in the index; on a match the
+    /// current value is read back and compared to rule out hash collisions.
+    ///
+    /// Default: false
+    pub skip_identical_writes: bool,
+    /// Terminate every log entry with a newline.
+    ///
+    /// Makes fragments readable line by line with tools like `cat` or `jq` at
+    /// the cost of one byte per entry. Fragments written in either mode can be
+    /// read in the other; compaction rewrites entries in the configured mode.
+    /// Only applies to the [`Codec::Json`] codec.
+    ///
+    /// Default: false
+    pub newline_delimited: bool,
+    /// Encoding of the log entries.
+    ///
+    /// When unset, the codec is detected from the oldest fragment of the
+    /// store. Every fragment of a store must use the same codec; forcing a
+    /// codec the store was not written with fails with
+    /// `StoreError::CodecMismatch`.
+    ///
+    /// Default: `None` (detect; [`Codec::Binary`] for new stores)
+    pub codec: Option<Codec>,
+    /// Amount of unreclaimed space, in bytes, that triggers a compaction.
+    ///
+    /// Default: 1MB
+    pub compaction_threshold: usize,
+    /// Run the compactions triggered by the threshold on a background thread
+    /// instead of as part of the write crossing it.
+    ///
+    /// Writes then never wait for a compaction to copy the live entries;
+    /// [`KvStore::sync`] waits for a running one to finish.
+    ///
+    /// Default: false
+    pub background_compaction: bool,
+    /// When writes are flushed and synced to disk.
+    ///
+    /// Default: [`SyncPolicy::OnFlush`]
+    pub sync_policy: SyncPolicy,
+    /// Size in bytes of the largest log entry, including its key and encoding
+    /// overhead.
+    ///
+    /// Larger writes fail with `StoreError::EntryTooLarge`. Larger entries
+    /// found while loading or reading fragments are treated as corruption, so
+    /// a malformed fragment can not make the store allocate huge buffers.
+    ///
+    /// Default: 64MB
+    pub max_entry_size: usize,
+    /// Size in bytes after which compaction continues in a new fragment.
+    ///
+    /// Compaction then produces several fragments of at most this size, unless
+    /// a single entry is larger. The active fragment is not bounded; it grows
+    /// until the next compaction.
+    ///
+    /// Default: `None` (compact into a single fragment)
+    pub max_fragment_bytes: Option<u64>,
+    /// Verify the checksum of every entry read by `get`.
+    ///
+    /// Checksums are always verified when loading fragments; skipping them on
+    /// reads saves hashing every value read, but may return corrupted values.
+    /// Only applies to the [`Codec::Binary`] codec.
+    ///
+    /// Default: true
+    pub verify_checksums: bool,
+    /// Memory in bytes that the read buffers of a handle's fragment readers
+    /// may take up collectively.
+    ///
+    /// Every fragment read keeps a buffered reader open; once opening another
+    /// would exceed the budget, idle readers of the oldest fragments are
+    /// closed first. Each clone of the store has its own readers and budget.
+    /// The reader being read from is always kept, so reads succeed even if
+    /// the budget is smaller than a single buffer.
+    ///
+    /// Default: `None` (unbounded)
+    pub reader_buffer_budget: Option<usize>,
+    /// Compression applied to values of at least `min_compress_bytes`.
+    ///
+    /// Values are only stored compressed if that makes them smaller, and are
+    /// decompressed transparently when read. Expiring values are never
+    /// compressed. Compaction copies compressed values as they are.
+    ///
+    /// Default: [`Compression::None`]
+    pub compression: Compression,
+    /// Size in bytes of the smallest value that is compressed.
+    ///
+    /// Default: 1KB
+    pub min_compress_bytes: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            initial_fragment: 0,
+            value_cache_capacity: 0,
+            trash_retention: None,
+            ordered_compaction: false,
+            skip_identical_writes: false,
+            newline_delimited: false,
+            codec: None,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            background_compaction: false,
+            sync_policy: SyncPolicy::default(),
+            max_entry_size: MAX_ENTRY_SIZE,
+            max_fragment_bytes: None,
+            verify_checksums: true,
+            reader_buffer_budget: None,
+            compression: Compression::default(),
+            min_compress_bytes: MIN_COMPRESS_BYTES,
+        }
+    }
+}
+
+/// Builder configuring and opening a [`KvStore`].
+///
+/// Every setter corresponds to a field of [`KvStoreOptions`] and defaults to
+/// the same value.
+#[derive(Debug, Clone, Default)]
+pub struct KvStoreBuilder {
+    options: KvStoreOptions,
+}
+
+impl KvStoreBuilder {
+    /// Sets the generation of the first fragment of a fresh store.
+    pub fn initial_fragment(mut self, fragment: u64) -> Self {
+        self.options.initial_fragment = fragment;
+        self
+    }
+
+    /// Sets the capacity in bytes of the value cache.
+    pub fn value_cache_capacity(mut self, capacity: usize) -> Self {
+        self.options.value_cache_capacity = capacity;
+        self
+    }
+
+    /// Keeps fragments replaced by a compaction in the trash for `retention`.
+    pub fn trash_retention(mut self, retention: Duration) -> Self {
+        self.options.trash_retention = Some(retention);
+        self
+    }
+
+    /// Sets whether compaction writes entries in key order.
+    pub fn ordered_compaction(mut self, ordered: bool) -> Self {
+        self.options.ordered_compaction = ordered;
+        self
+    }
+
+    /// Sets whether writes of a key's current value are skipped.
+    pub fn skip_identical_writes(mut self, skip: bool) -> Self {
+        self.options.skip_identical_writes = skip;
+        self
+    }
+
+    /// Sets whether JSON log entries are terminated with a newline.
+    pub fn newline_delimited(mut self, delimited: bool) -> Self {
+        self.options.newline_delimited = delimited;
+        self
+    }
+
+    /// Forces the codec of the log entries instead of detecting it.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.options.codec = Some(codec);
+        self
+    }
+
+    /// Sets the amount of unreclaimed space, in bytes, that triggers a
+    /// compaction.
+    pub fn compaction_threshold(mut self, threshold: usize) -> Self {
+        self.options.compaction_threshold = threshold;
+        self
+    }
+
+    /// Sets whether triggered compactions run on a background thread.
+    pub fn background_compaction(mut self, background: bool) -> Self {
+        self.options.background_compaction = background;
+        self
+    }
+
+    /// Sets when writes are flushed and synced to disk.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.options.sync_policy = policy;
+        self
+    }
+
+    /// Sets the size in bytes of the largest log entry.
+    pub fn max_entry_size(mut self, size: usize) -> Self {
+        self.options.max_entry_size = size;
+        self
+    }
+
+    /// Bounds the size in bytes of the fragments written by compaction.
+    pub fn max_fragment_bytes(mut self, size: u64) -> Self {
+        self.options.max_fragment_bytes = Some(size);
+        self
+    }
+
+    /// Sets whether `get` verifies the checksums of the entries it reads.
+    pub fn verify_checksums(mut self, verify: bool) -> Self {
+        self.options.verify_checksums = verify;
+        self
+    }
+
+    /// Bounds the memory in bytes taken up by the read buffers of a handle's
+    /// fragment readers.
+    pub fn reader_buffer_budget(mut self, budget: usize) -> Self {
+        self.options.reader_buffer_budget = Some(budget);
+        self
+    }
+
+    /// Sets the compression applied to large values.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    /// Sets the size in bytes of the smallest value that is compressed.
+    pub fn min_compress_bytes(mut self, size: usize) -> Self {
+        self.options.min_compress_bytes = size;
+        self
+    }
+
+    /// Opens the store at the given directory path with the configured
+    /// options.
+    pub fn open(self, dir: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(dir, self.options)
+    }
+}
+
+/// Compression of the values written to the log.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Values are written as they are.
+    #[default]
+    None,
+    /// Values are compressed with zstd at the given level.
+    Zstd {
+        /// Compression level, from 1 (fastest) to 22 (smallest); 0 selects
+        /// zstd's default level
+        level: i32,
+    },
+}
+
+/// Durability of writes, trading throughput for crash safety.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync the active fragment to disk after every write.
+    ///
+    /// A write survives a machine crash once it returns, but every write
+    /// waits for the disk.
+    Always,
+    /// Flush every write to the operating system; only `sync` syncs to disk.
+    ///
+    /// A write survives a crash of the process once it returns, but may be
+    /// lost if the machine crashes before the operating system persists it.
+    #[default]
+    OnFlush,
+    /// Buffer writes in memory until the buffer fills, `sync` is called or
+    /// the store is dropped.
+    ///
+    /// Buffered writes are lost if the process crashes or the store is never
+    /// dropped. Fragments written by compaction are not synced either. Reads
+    /// flush the buffer first, so they wait for concurrent writes.
+    Never,
+}
+
+/// Policy deciding which value wins when merging a key present in both stores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the value already present in the destination store.
+    KeepMine,
+    /// Overwrite the destination value with the value from the other store.
+    TakeTheirs,
+}
+
+/// Represents a key-value store.
+///
+/// Cloning a store returns another handle to the same data, so a store can be
+/// shared across threads by handing every thread its own clone. Clones share
+/// the index, the value cache and the log writer; writes are serialized by a
+/// lock while reads run concurrently, each clone using its own fragment
+/// readers.
+pub struct KvStore {
+    shared: Arc<SharedStore>,
+    readers: RefCell<HashMap<u64, BufReader<File>>>,
+}
+
+/// State shared by every clone of a [`KvStore`].
+struct SharedStore {
+    dir: PathBuf,
+    index: RwLock<Index>,
+    log: Mutex<LogWriter>,
+    cache: Mutex<ValueCache>,
+    /// Oldest fragment generation still part of the store; readers of older
+    /// fragments are stale.
+    oldest_fragment: AtomicU64,
+    /// Held for the whole of a compaction, so only one runs at a time
+    compaction: Mutex<()>,
+    /// Thread running a background compaction, if any was started
+    background: Mutex<Option<JoinHandle<()>>>,
+    trash_retention: Option<Duration>,
+    ordered_compaction: bool,
+    skip_identical_writes: bool,
+    newline_delimited: bool,
+    codec: Codec,
+    compaction_threshold: usize,
+    background_compaction: bool,
+    sync_policy: SyncPolicy,
+    max_entry_size: usize,
+    max_fragment_bytes: Option<u64>,
+    verify_checksums: bool,
+    reader_buffer_budget: Option<usize>,
+    compression: Compression,
+    min_compress_bytes: usize,
+}
+
+/// Writer of a fragment along with its generation.
+type FragmentWriter = (u64, BufWriter<File>);
+
+/// Entries moved by a compaction, by namespace and key.
+type MovedEntries = HashMap<(String, String), EntryPosition>;
+
+/// Writing end of the log, only accessed while holding the store's log lock.
+struct LogWriter {
+    fragment: u64,
+    write_pos: u64,
+    writer: BufWriter<File>,
+    fragments: BTreeSet<u64>,
+    unreclaimed_space: usize,
+    /// Compactions run since the store was opened
+    compactions: u64,
+    /// Space reclaimed by those compactions, in bytes
+    reclaimed_space: u64,
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+            readers: RefCell::default(),
+        }
+    }
+}
+
+impl KvStore {
+    /// Opens a key-value store at the given directory path.
+    ///
+    ///
+    /// If Key-Value store exists at the path, the pre-existing stores index is
+    /// loaded into memory and subsequent changes are stored.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(dir, KvStoreOptions::default())
+    }
+
+    /// Returns a builder for opening a store with non-default options.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    /// Opens a key-value store at the given directory path using the provided
+    /// options.
+    pub fn open_with_options(dir: impl Into<PathBuf>, options: KvStoreOptions) -> Result<Self> {
+        let dir: PathBuf = dir.into();
+        let mut index = Index::default();
+
+        // Load all pre-existing fragments; later fragments must be loaded last
+        // so their entries replace the ones in older fragments.
+        let mut paths = dir
+            .read_dir()?
+            .filter(|res| res.is_ok())
+            .map(|res| res.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .map(|ext| ext == LOG_EXTENSION)
+                    .unwrap_or(false)
+            })
+            .map(|path| fragment_generation(&path).map(|frag| (frag, path)))
+            .collect::<Result<Vec<(u64, PathBuf)>>>()?;
+        paths.sort_unstable();
+        // Loading either of two fragments claiming the same generation would
+        // silently drop the other's entries.
+        if let Some(pair) = paths.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(StoreError::Fragment(format!(
+                "fragments {} and {} both claim generation {}; remove or rename one of them",
+                pair[0].1.display(),
+                pair[1].1.display(),
+                pair[0].0
+            )));
+        }
+        // Fragments a compaction failed to remove would resurrect the keys
+        // removed before it; they stay listed so the next compaction retries.
+        let replaced_before = read_replaced_before(&dir)?;
+        let replaced: Vec<u64> = paths
+            .iter()
+            .map(|(fragment, _)| *fragment)
+            .take_while(|fragment| *fragment < replaced_before)
+            .collect();
+        if !replaced.is_empty() {
+            warn!(
+                target: "kvs::engine",
+                fragments = ?replaced,
+                "skipping fragments replaced by a compaction"
+            );
+            paths.drain(..replaced.len());
+        }
+
+        let codec = match (options.codec, detect_codec(&paths)?) {
+            (Some(requested), Some(found)) if requested != found => {
+                return Err(StoreError::CodecMismatch { requested, found });
+            }
+            (requested, found) => requested.or(found).unwrap_or_default(),
+        };
+        let (mut fragment_readers, unreclaimed_space) =
+            load_fragments(paths, codec, options.max_entry_size, &mut index)?;
+        let mut fragment = fragment_readers
+            .keys()
+            .max()
+            .copied()
+            .unwrap_or(options.initial_fragment.max(replaced_before));
+        let oldest_fragment = fragment_readers.keys().min().copied().unwrap_or(u64::MAX);
+
+        if oldest_fragment < options.initial_fragment {
+            return Err(StoreError::Fragment(format!(
+                "existing fragment {} collides with initial fragment {}",
+                oldest_fragment, options.initial_fragment
+            )));
+        }
+
+        // Open latest fragment for read or create a new fragment
+        // if non exist
+        let file = if fragment_readers.is_empty() {
+            let file = new_fragment(&dir.join(fragment_filename(fragment)), codec)?;
+            fragment_readers.insert(fragment, BufReader::new(file.try_clone()?));
+            file
+        } else {
+            let (active, file) =
+                open_active_fragment(&dir, fragment, codec, &mut fragment_readers)?;
+            fragment = active;
+            file
+        };
+        if let Some(budget) = options.reader_buffer_budget {
+            evict_readers(&mut fragment_readers, budget);
+        }
+        let mut writer = BufWriter::new(file);
+        let write_pos = writer.seek(SeekFrom::End(0))?;
+
+        let log = LogWriter {
+            fragment,
+            write_pos,
+            writer,
+            fragments: fragment_readers.keys().copied().chain(replaced).collect(),
+            unreclaimed_space,
+            compactions: 0,
+            reclaimed_space: 0,
+        };
+        debug!(
+            target: "kvs::engine",
+            fragments = log.fragments.len(),
+            active_fragment = log.fragment,
+            keys = index.len(),
+            unreclaimed_space = log.unreclaimed_space,
+            "opened store"
+        );
+        let mut store = Self {
+            shared: Arc::new(SharedStore {
+                dir,
+                index: RwLock::new(index),
+                log: Mutex::new(log),
+                cache: Mutex::new(ValueCache::new(options.value_cache_capacity)),
+                oldest_fragment: AtomicU64::new(oldest_fragment.min(fragment)),
+                compaction: Mutex::new(()),
+                background: Mutex::new(None),
+                trash_retention: options.trash_retention,
+                ordered_compaction: options.ordered_compaction,
+                skip_identical_writes: options.skip_identical_writes,
+                newline_delimited: options.newline_delimited && codec == Codec::Json,
+                codec,
+                compaction_threshold: options.compaction_threshold,
+                background_compaction: options.background_compaction,
+                sync_policy: options.sync_policy,
+                max_entry_size: options.max_entry_size,
+                max_fragment_bytes: options.max_fragment_bytes,
+                verify_checksums: options.verify_checksums,
+                reader_buffer_budget: options.reader_buffer_budget,
+                compression: options.compression,
+                min_compress_bytes: options.min_compress_bytes,
+            }),
+            readers: RefCell::new(fragment_readers),
+        };
+        store.compact_if_needed()?;
+        Ok(store)
+    }
+
+    /// Gets the value of a key.
+    ///
+    /// Unlike writes, reads only need a shared reference, so they can run
+    /// concurrently on clones of the store.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let trace = OpTrace::start("get", &key);
+        let cached = self.shared.lock_cache().get(&key);
+        let result = match cached {
+            Some(value) => Ok(Some(value.to_string())),
+            None => self
+                .read_value(&key)
+                .map(|value| value.map(|value| value.to_string())),
+        };
+        trace.finish(result, |value| value.as_ref().map_or(0, String::len))
+    }
+
+    /// Returns the size in bytes of the key's current log entry on disk.
+    ///
+    /// This is the size of the whole serialized entry, including the key and
+    /// the encoding overhead, not just the length of the value. Returns `None`
+    /// if the key does not exist.
+    pub fn entry_disk_size(&self, key: &str) -> Option<usize> {
+        let now = now_millis();
+        self.shared
+            .read_index()
+            .get(DEFAULT_NAMESPACE, key)
+            .filter(|ep| !ep.is_expired(now))
+            .map(|ep| ep.size)
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// Once expired the key reads as absent. Its entry is dropped from the
+    /// index on the next read of the key and from the log by the next
+    /// compaction. Setting the key again, with or without a TTL, replaces the
+    /// expiry.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let mut log = self.shared.lock_log();
+        let value_hash = hash_value(value.as_bytes());
+        let entry = LogEntry::SetEx {
+            ns: String::new(),
+            key: key.clone(),
+            value,
+            expires_at,
+        };
+        self.write_set(
+            &mut log,
+            DEFAULT_NAMESPACE,
+            key,
+            &entry,
+            value_hash,
+            Some(expires_at),
+        )
+    }
+
+    /// Removes a key only if its current value equals `expected`.
+    ///
+    /// Returns whether the key was removed. A missing key is not an error and
+    /// returns `false`, since there is no value that could match. The value
+    /// is compared and removed under the log lock, so no write of another
+    /// clone can happen in between.
+    pub fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        let mut log = self.shared.lock_log();
+        match self.read_locked(&mut log, DEFAULT_NAMESPACE, &key)? {
+            Some(value) if value == expected => {
+                self.remove_locked(&mut log, DEFAULT_NAMESPACE, key)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Removes a key, treating a missing key as already removed.
+    ///
+    /// Unlike `remove`, this does not return `StoreError::NotFound`; instead it
+    /// returns whether the key existed and was removed.
+    pub fn remove_idempotent(&mut self, key: String) -> Result<bool> {
+        match self.remove(key) {
+            Ok(()) => Ok(true),
+            Err(StoreError::NotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the location of every live key in the log fragments.
+    ///
+    /// The map is a copy of the in-memory index; it is not updated by later
+    /// writes or compactions.
+    pub fn location_map(&self) -> HashMap<String, EntryPosition> {
+        self.shared
+            .read_index()
+            .namespace(DEFAULT_NAMESPACE)
+            .iter()
+            .map(|(key, ep)| (key.clone(), ep.clone()))
+            .collect()
+    }
+
+    /// Sets the values of multiple keys, flushing the log once at the end
+    /// instead of after every entry.
+    ///
+    /// Entries are written contiguously in order, so a key appearing more than
+    /// once ends up with its last value. The whole batch is made durable
+    /// according to the [`SyncPolicy`] and compaction, if needed, runs once
+    /// after it is written.
+    ///
+    /// Every entry is encoded before any is written, so an entry that can not
+    /// be encoded, e.g. because it is too large, fails the batch without
+    /// writing any of it.
+    pub fn set_batch(&mut self, entries: Vec<(String, String)>) -> Result<()> {
+        let mut log = self.shared.lock_log();
+        let start = log.write_pos;
+        let mut batch = Vec::new();
+        // Entries of the batch by key, along with their values if repeats of
+        // them are to be skipped; they are not on disk to compare with yet.
+        let mut written: BTreeMap<String, (EntryPosition, Option<String>)> = BTreeMap::new();
+        let mut unreclaimed_space = 0;
+        for (key, value) in entries {
+            let value_hash = hash_value(value.as_bytes());
+            let identical = match written.get(&key) {
+                Some((ep, batched)) => {
+                    ep.value_hash == value_hash && batched.as_ref() == Some(&value)
+                }
+                None => {
+                    let current = self
+                        .shared
+                        .read_index()
+                        .get(DEFAULT_NAMESPACE, &key)
+                        .cloned();
+                    self.is_identical_write(
+                        &mut log,
+                        DEFAULT_NAMESPACE,
+                        &key,
+                        value.as_bytes(),
+                        value_hash,
+                        current.as_ref(),
+                    )?
+                }
+            };
+            if identical {
+                continue;
+            }
+
+            let batched = self.shared.skip_identical_writes.then(|| value.clone());
+            let entry = match self.shared.compress(value.as_bytes())? {
+                Some(value) => LogEntry::SetCompressed {
+                    ns: String::new(),
+                    key: key.clone(),
+                    value,
+                },
+                None => LogEntry::Set {
+                    ns: String::new(),
+                    key: key.clone(),
+                    value,
+                },
+            };
+            let pos = start + batch.len() as u64;
+            batch.extend_from_slice(&self.shared.encode(&entry)?);
+            let ep = (log.fragment, pos..start + batch.len() as u64, value_hash).into();
+            if let Some((prev, _)) = written.insert(key, (ep, batched)) {
+                unreclaimed_space += prev.size;
+            }
+        }
+
+        log.writer.write_all(&batch)?;
+        log.write_pos += batch.len() as u64;
+        log.unreclaimed_space += unreclaimed_space;
+        // Entries only become visible once they are readable from disk.
+        self.shared.sync_write(&mut log)?;
+        for (key, (ep, _)) in written {
+            self.shared
+                .insert_index(&mut log, DEFAULT_NAMESPACE, key, ep);
+        }
+        self.compact_if_needed_locked(&mut log)
+    }
+
+    /// Returns an iterator over every live key, sorted.
+    ///
+    /// Keys are read from the in-memory index without touching disk. The
+    /// iterator walks a snapshot of the keys taken when it is created.
+    pub fn keys(&self) -> impl Iterator<Item = String> {
+        let now = now_millis();
+        let keys: Vec<String> = self
+            .shared
+            .read_index()
+            .namespace(DEFAULT_NAMESPACE)
+            .iter()
+            .filter(|(_, ep)| !ep.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.into_iter()
+    }
+
+    /// Returns whether the store holds a live value for `key`.
+    ///
+    /// Only consults the in-memory index; the value is not read.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.shared
+            .read_index()
+            .get(DEFAULT_NAMESPACE, key)
+            .is_some_and(|ep| !ep.is_expired(now_millis()))
+    }
+
+    /// Returns the number of live keys in the store.
+    pub fn len(&self) -> usize {
+        let now = now_millis();
+        let index = self.shared.read_index();
+        index
+            .namespace(DEFAULT_NAMESPACE)
+            .values()
+            .filter(|ep| !ep.is_expired(now))
+            .count()
+    }
+
+    /// Returns whether the store holds no live keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns every live key-value pair of the store, sorted by key.
+    ///
+    /// Every value is read into memory, so this is intended for tests and
+    /// tooling on small stores rather than for iterating large ones.
+    pub fn to_map(&mut self) -> Result<BTreeMap<String, String>> {
+        Ok(self.scan("")?.into_iter().collect())
+    }
+
+    /// Copies all live entries of `other` into this store.
+    ///
+    /// Entries of every namespace are copied along with their expiry, and
+    /// byte values stay byte values. Keys present in both stores are resolved
+    /// using the `conflict` policy. Returns the number of keys written to this
+    /// store.
+    ///
+    /// Every entry is read and encoded before any is written, so an entry
+    /// that can not be copied leaves this store unchanged.
+    pub fn merge_from(&mut self, other: &mut KvStore, conflict: ConflictPolicy) -> Result<usize> {
+        let now = now_millis();
+        let live: Vec<(String, String, EntryPosition)> = other
+            .shared
+            .read_index()
+            .iter()
+            .filter(|(_, _, ep)| !ep.is_expired(now))
+            .map(|(ns, key, ep)| (ns.to_owned(), key.clone(), ep.clone()))
+            .collect();
+        other.shared.lock_log().writer.flush()?;
+
+        let mut entries = Vec::with_capacity(live.len());
+        for (ns, key, ep) in live {
+            let value = other.read_entry(&ns, &key, &ep)?;
+            let value_hash = hash_value(&value);
+            let entry = match (ep.expires_at, self.shared.compress(&value)?) {
+                (Some(expires_at), _) => LogEntry::SetEx {
+                    ns: ns.clone(),
+                    key: key.clone(),
+                    value: String::from_utf8(value)?,
+                    expires_at,
+                },
+                (None, Some(value)) => LogEntry::SetCompressed {
+                    ns: ns.clone(),
+                    key: key.clone(),
+                    value,
+                },
+                // Byte values that are valid UTF-8 read the same either way.
+                (None, None) => match String::from_utf8(value) {
+                    Ok(value) => LogEntry::Set {
+                        ns: ns.clone(),
+                        key: key.clone(),
+                        value,
+                    },
+                    Err(err) => LogEntry::SetBytes {
+                        ns: ns.clone(),
+                        key: key.clone(),
+                        value: err.into_bytes(),
+                    },
+                },
+            };
+            let buf = self.shared.encode(&entry)?;
+            entries.push((ns, key, buf, value_hash, ep.expires_at));
+        }
+
+        let mut log = self.shared.lock_log();
+        let mut written = Vec::new();
+        for (ns, key, buf, value_hash, expires_at) in entries {
+            if conflict == ConflictPolicy::KeepMine {
+                self.shared.evict_expired(&mut log, Some((&ns, &key)));
+                if self.shared.read_index().get(&ns, &key).is_some() {
+                    continue;
+                }
+            }
+            let pos = log.write_pos;
+            log.writer.write_all(&buf)?;
+            log.write_pos += buf.len() as u64;
+            let mut ep: EntryPosition = (log.fragment, pos..log.write_pos, value_hash).into();
+            ep.expires_at = expires_at;
+            written.push((ns, key, ep));
+        }
+
+        // Entries only become visible once they are readable from disk.
+        self.shared.sync_write(&mut log)?;
+        let merged = written.len();
+        for (ns, key, ep) in written {
+            self.shared.insert_index(&mut log, &ns, key, ep);
+        }
+        self.compact_if_needed_locked(&mut log)?;
+        Ok(merged)
+    }
+
+    /// Returns the amount of unreclaimed space, in bytes, that triggers a
+    /// compaction.
+    pub fn compaction_threshold(&self) -> usize {
+        self.shared.compaction_threshold
+    }
+
+    /// Returns the number of log fragments currently making up the store.
+    pub fn fragment_count(&self) -> usize {
+        self.shared.lock_log().fragments.len()
+    }
+
+    /// Returns the generation of the fragment new entries are written to.
+    pub fn active_fragment(&self) -> u64 {
+        self.shared.lock_log().fragment
+    }
+
+    /// Returns the amount of space, in bytes, taken up by stale entries that
+    /// will be reclaimed by the next compaction.
+    pub fn unreclaimed_space(&self) -> usize {
+        self.shared.lock_log().unreclaimed_space
+    }
+
+    /// Returns the number of compactions run since the store was opened.
+    pub fn compactions(&self) -> u64 {
+        self.shared.lock_log().compactions
+    }
+
+    /// Returns the amount of space, in bytes, reclaimed by compactions since
+    /// the store was opened.
+    pub fn reclaimed_space(&self) -> u64 {
+        self.shared.lock_log().reclaimed_space
+    }
+
+    /// Reads the values of the given keys into the value cache, so that
+    /// subsequent gets of them are served from memory.
+    ///
+    /// Keys are read in the order of their entries in the log, sweeping every
+    /// fragment once instead of seeking back and forth. Missing, expiring and
+    /// already cached keys are skipped. Values only stay cached while the
+    /// cache has room for them; with the cache disabled this does nothing.
+    pub fn prefetch(&self, keys: &[String]) -> Result<()> {
+        if !self.shared.lock_cache().is_enabled() {
+            return Ok(());
+        }
+
+        let mut positions: Vec<(u64, u64, &String)> = {
+            let index = self.shared.read_index();
+            keys.iter()
+                .filter_map(|key| index.get(DEFAULT_NAMESPACE, key).map(|ep| (key, ep)))
+                .filter(|(_, ep)| ep.expires_at.is_none())
+                .map(|(key, ep)| (ep.fragment, ep.pos, key))
+                .collect()
+        };
+        positions.sort_unstable();
+        for (_, _, key) in positions {
+            if !self.shared.lock_cache().contains(key) {
+                self.read_value(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `scan`, but keeps going when reading a value fails.
+    ///
+    /// Returns the key-value pairs that were read, sorted by key, along with
+    /// the error of every key whose value could not be read. Intended for
+    /// recovering what is left of a partially corrupt store.
+    pub fn scan_lenient(&self, prefix: &str) -> (Vec<(String, String)>, KeyErrors) {
+        let mut pairs = Vec::new();
+        let mut errors = Vec::new();
+        for key in self.prefix_keys(prefix) {
+            match self.get(key.clone()) {
+                Ok(Some(value)) => pairs.push((key, value)),
+                Ok(None) => {}
+                Err(err) => errors.push((key, err)),
+            }
+        }
+        (pairs, errors)
+    }
+
+    /// Gets the value of a key as a shared string.
+    ///
+    /// With the value cache enabled, repeated gets of a cached key return the
+    /// same allocation instead of copying the value. Without the cache every
+    /// call allocates, like `get`.
+    pub fn get_shared(&self, key: String) -> Result<Option<Arc<str>>> {
+        if let Some(value) = self.shared.lock_cache().get(&key) {
+            return Ok(Some(value));
+        }
+
+        self.read_value(&key)
+    }
+
+    /// Sets the value of a key, returning its previous value.
+    ///
+    /// Like `HashMap::insert`, returns `None` if the key was not set. The
+    /// previous value is read before the new one is written, and no other
+    /// write can happen in between.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreError::Utf8` without writing anything if the previous
+    /// value is a byte value that is not valid UTF-8.
+    pub fn set_returning(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let trace = OpTrace::start("set_returning", &key);
+        let bytes = value.len();
+        let mut log = self.shared.lock_log();
+        let result = self
+            .read_locked(&mut log, DEFAULT_NAMESPACE, &key)
+            .and_then(|previous| {
+                self.set_locked(&mut log, DEFAULT_NAMESPACE, key, value)?;
+                Ok(previous)
+            });
+        drop(log);
+        trace.finish(result, |_| bytes)
+    }
+
+    /// Removes a key, returning the value it held.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreError::NotFound` if the key does not exist, like
+    /// `remove`, and `StoreError::Utf8` without removing the key if its value
+    /// is a byte value that is not valid UTF-8.
+    pub fn remove_returning(&mut self, key: String) -> Result<String> {
+        let trace = OpTrace::start("remove_returning", &key);
+        let mut log = self.shared.lock_log();
+        let result = self
+            .read_locked(&mut log, DEFAULT_NAMESPACE, &key)
+            .and_then(|previous| previous.ok_or(StoreError::NotFound))
+            .and_then(|previous| {
+                self.remove_locked(&mut log, DEFAULT_NAMESPACE, key)?;
+                Ok(previous)
+            });
+        drop(log);
+        trace.finish(result, String::len)
+    }
+
+    /// Sets the value of a key to arbitrary bytes.
+    ///
+    /// Unlike `set`, the value need not be valid UTF-8. Byte values can be
+    /// read with `get_bytes`; `get` only reads those that are valid UTF-8.
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        let trace = OpTrace::start("set_bytes", &key);
+        let bytes = value.len();
+        trace.finish(self.apply_set_bytes(key, value), |_| bytes)
+    }
+
+    /// Gets the value of a key as bytes.
+    ///
+    /// Reads values written by both `set` and `set_bytes`.
+    pub fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let trace = OpTrace::start("get_bytes", &key);
+        let cached = self.shared.lock_cache().get(&key);
+        let result = match cached {
+            Some(value) => Ok(Some(value.as_bytes().to_vec())),
+            None => self.read_current(DEFAULT_NAMESPACE, &key, |store| {
+                store.read_flushed_bytes(DEFAULT_NAMESPACE, &key)
+            }),
+        };
+        trace.finish(result, |value| value.as_ref().map_or(0, Vec::len))
+    }
+
+    /// Returns a handle to the keys of namespace `name`.
+    ///
+    /// Namespaces partition the store's keys without opening another
+    /// directory: the same key holds independent values in every namespace,
+    /// including the default one, `""`, holding the keys written to the store
+    /// directly. Methods of the store itself, like `keys`, `len` and `scan`,
+    /// only see the default namespace.
+    pub fn namespace(&mut self, name: impl Into<String>) -> NamespaceHandle<'_> {
+        NamespaceHandle {
+            store: self,
+            name: name.into(),
+        }
+    }
+
+    /// Returns the memory in bytes taken up by the read buffers of this
+    /// handle's fragment readers.
+    pub fn reader_buffer_memory(&self) -> usize {
+        reader_buffer_memory(&self.readers.borrow())
+    }
+
+    /// Returns the number of reads served from the value cache and the number
+    /// of reads that missed it, respectively.
+    pub fn cache_stats(&self) -> (u64, u64) {
+        let cache = self.shared.lock_cache();
+        (cache.hits, cache.misses)
+    }
+
+    /// Flushes buffered writes to the operating system, regardless of the
+    /// [`SyncPolicy`].
+    ///
+    /// Combined with `SyncPolicy::Never` this lets callers batch writes and
+    /// choose when they are persisted. Flushed writes survive a crash of the
+    /// process; use `sync` to also survive a crash of the machine.
+    pub fn flush(&mut self) -> Result<()> {
+        self.shared.lock_log().writer.flush()?;
+        Ok(())
+    }
+
+    /// Flushes buffered writes and closes this handle to the store.
+    ///
+    /// Unlike dropping the store, which flushes too, this reports a failed
+    /// flush. Clones of the store stay open.
+    pub fn close(mut self) -> Result<()> {
+        self.flush()
+    }
+
+    /// Makes all previous writes durable, regardless of the [`SyncPolicy`].
+    ///
+    /// Flushes the writer and syncs the active fragment to disk. A running
+    /// background compaction is waited for first, so once this returns the
+    /// store has no pending work.
+    pub fn sync(&mut self) -> Result<()> {
+        let background = self.shared.lock_background().take();
+        if let Some(handle) = background {
+            // The compaction logs its own failures.
+            let _ = handle.join();
+        }
+        let mut log = self.shared.lock_log();
+        log.writer.flush()?;
+        log.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Discards the in-memory index and rebuilds it from the fragments on
+    /// disk, as opening the store would.
+    ///
+    /// Buffered writes are flushed first so they are part of the rebuilt
+    /// index. The unreclaimed space is recounted and the value cache and this
+    /// handle's fragment readers are dropped along with the old index.
+    pub fn reindex(&mut self) -> Result<()> {
+        let _compacting = self.shared.lock_compaction();
+        let mut log = self.shared.lock_log();
+        log.writer.flush()?;
+
+        // Fragments replaced by a compaction but not yet removed would
+        // resurrect the keys removed before it.
+        let oldest = self.shared.oldest_fragment.load(Ordering::Acquire);
+        let paths = log
+            .fragments
+            .range(oldest..)
+            .map(|&fragment| (fragment, self.shared.dir.join(fragment_filename(fragment))))
+            .collect();
+        let mut index = Index::default();
+        let (mut readers, unreclaimed_space) = load_fragments(
+            paths,
+            self.shared.codec,
+            self.shared.max_entry_size,
+            &mut index,
+        )?;
+        if let Some(budget) = self.shared.reader_buffer_budget {
+            evict_readers(&mut readers, budget);
+        }
+
+        debug!(
+            target: "kvs::engine",
+            keys = index.len(),
+            unreclaimed_space,
+            "rebuilt index"
+        );
+        *self.shared.write_index() = index;
+        self.shared.lock_cache().clear();
+        log.unreclaimed_space = unreclaimed_space;
+        *self.readers.borrow_mut() = readers;
+        Ok(())
+    }
+
+    /// Compacts the Key-Value databases log.
+    ///
+    /// Compaction clears outdated entries from the stores log fragments, generating
+    /// a new log fragment with up to date values.
+    ///
+    /// Live entries are copied from a snapshot of the index without holding
+    /// the log lock, so other clones of the store keep reading and writing
+    /// meanwhile. Only the entries written during the copy are copied with
+    /// the lock held, before the new fragments replace the old ones.
+    ///
+    /// The new fragment is fully written, synced and renamed into place before
+    /// any of the stores state is modified; a failed compaction leaves the
+    /// store exactly as it was.
+    ///
+    /// Returns the generations of the fragments the compaction replaced, in
+    /// ascending order, so callers can run their own cleanup for them. The
+    /// fragments are already deleted, or moved to the trash directory if
+    /// `trash_retention` is set. Other clones of the store release their
+    /// handles to them on their next read. Fragments that could not be
+    /// removed are logged and left out; the next compaction retries them, and
+    /// reopening the store skips them meanwhile.
+    pub fn compact(&mut self) -> Result<Vec<u64>> {
+        let _compacting = self.shared.lock_compaction();
+        let snapshot = self.begin_compaction(&mut self.shared.lock_log())?;
+        let copied = self.write_compacted(&snapshot);
+        self.finish_compaction(&mut self.shared.lock_log(), snapshot, copied)
+    }
+
+    /// Compacts the log while holding the log lock.
+    ///
+    /// Does nothing if another handle is already compacting the store; that
+    /// compaction reclaims the space instead.
+    fn compact_locked(&self, log: &mut LogWriter) -> Result<()> {
+        let _compacting = match self.shared.compaction.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return Ok(()),
+        };
+        let snapshot = self.begin_compaction(log)?;
+        let copied = self.write_compacted(&snapshot);
+        self.finish_compaction(log, snapshot, copied)?;
+        Ok(())
+    }
+
+    /// Starts a compaction by taking a snapshot of the live entries to copy.
+    fn begin_compaction(&self, log: &mut LogWriter) -> Result<CompactionSnapshot> {
+        // Buffered entries must be readable to be copied.
+        log.writer.flush()?;
+        // Expired entries read as absent either way, so they are dropped even
+        // if the compaction fails.
+        self.shared.evict_expired(log, None);
+        let new_gen = log.fragment + 1;
+        info!(
+            target: "kvs::engine",
+            fragment = new_gen,
+            unreclaimed_space = log.unreclaimed_space,
+            "compaction started"
+        );
+        let index = self.shared.read_index();
+        let entries = index.iter().map(|(ns, key, ep)| ((ns, key.as_str()), ep));
+        let entries = compaction_order(entries, self.shared.ordered_compaction)
+            .into_iter()
+            .map(|((ns, key), ep)| ((ns.to_owned(), key.to_owned()), ep.clone()))
+            .collect();
+        Ok(CompactionSnapshot {
+            new_gen,
+            entries,
+            start: Instant::now(),
+        })
+    }
+
+    /// Completes a compaction once the snapshot's entries are `copied`.
+    ///
+    /// Entries written since the snapshot are copied as well, and keys
+    /// removed since get a tombstone, so the new fragments hold exactly the
+    /// current state of the store. They then replace the old fragments.
+    fn finish_compaction(
+        &self,
+        log: &mut LogWriter,
+        snapshot: CompactionSnapshot,
+        copied: Result<(CompactionOutput, Vec<EntryPosition>)>,
+    ) -> Result<Vec<u64>> {
+        let dir = &self.shared.dir;
+        let new_gen = snapshot.new_gen;
+        // Store new fragments in temporary files till the compaction is
+        // succesful. Avoid corrupting the stores directory due to failed
+        // compaction.
+        let res = copied.and_then(|(mut output, positions)| {
+            log.writer.flush()?;
+            let (moved, stale) = self.copy_changes(&mut output, &snapshot, &positions)?;
+            output.sync(&self.shared)?;
+            let fragments = output.fragments;
+            for (i, (fragment, _)) in fragments.iter().enumerate() {
+                let path = dir.join(fragment_filename(*fragment));
+                if let Err(err) = std::fs::rename(temp_fragment_path(dir, *fragment), path) {
+                    // Compacted fragments left behind would shadow the
+                    // writes following this compaction when reopening.
+                    for (renamed, _) in &fragments[..i] {
+                        let _ = std::fs::remove_file(dir.join(fragment_filename(*renamed)));
+                    }
+                    return Err(err.into());
+                }
+            }
+            Ok((positions, moved, stale, fragments))
+        });
+        let (positions, moved, stale, mut fragments) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                warn!(target: "kvs::engine", fragment = new_gen, error = %err, "compaction failed");
+                let mut fragment = new_gen;
+                while std::fs::remove_file(temp_fragment_path(dir, fragment)).is_ok() {
+                    fragment += 1;
+                }
+                return Err(err);
+            }
+        };
+
+        // Compaction is done; old versions are safe to delete now.
+        let reclaimed = log.unreclaimed_space;
+        let (active, writer) = fragments.pop().expect("compaction writes a fragment");
+        log.writer = writer;
+        log.write_pos = log.writer.stream_position()?;
+        log.fragment = active;
+        {
+            let copied: HashMap<(&str, &str), usize> = snapshot
+                .entries
+                .iter()
+                .enumerate()
+                .map(|(i, ((ns, key), _))| ((ns.as_str(), key.as_str()), i))
+                .collect();
+            let mut index = self.shared.write_index();
+            for (ns, key, ep) in index.iter_mut() {
+                if let Some(&i) = copied.get(&(ns, key.as_str())) {
+                    *ep = positions[i].clone();
+                }
+            }
+            for ((ns, key), new_ep) in moved {
+                if let Some(ep) = index.get_mut(&ns, &key) {
+                    *ep = new_ep;
+                }
+            }
+        }
+        log.unreclaimed_space = stale;
+        log.compactions += 1;
+        log.reclaimed_space += reclaimed as u64;
+        self.shared
+            .oldest_fragment
+            .store(new_gen, Ordering::Release);
+
+        // No read can reach the old fragments through the index anymore; the
+        // new fragments are opened for reading on demand.
+        self.readers.borrow_mut().clear();
+        if let Some(retention) = self.shared.trash_retention {
+            // Purging is housekeeping; the compaction has already committed.
+            if let Err(err) = purge_trash(dir, retention) {
+                warn!(target: "kvs::engine", error = %err, "failed to purge trashed fragments");
+            }
+        }
+        let replaced = std::mem::replace(&mut log.fragments, (new_gen..=active).collect());
+        let mut removed = Vec::with_capacity(replaced.len());
+        for old_fragment in replaced {
+            let res = match self.shared.trash_retention {
+                Some(_) => trash_fragment(dir, old_fragment),
+                None => std::fs::remove_file(dir.join(fragment_filename(old_fragment)))
+                    .map_err(StoreError::from),
+            };
+            match res {
+                Ok(()) => removed.push(old_fragment),
+                Err(StoreError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                    removed.push(old_fragment)
+                }
+                Err(err) => {
+                    // The compaction has committed, so it is not failed over
+                    // this; keeping the fragment listed makes the next
+                    // compaction retry removing it.
+                    warn!(
+                        target: "kvs::engine",
+                        fragment = old_fragment,
+                        error = %err,
+                        "failed to remove replaced fragment"
+                    );
+                    log.fragments.insert(old_fragment);
+                }
+            }
+        }
+        // Reopening the store must not load the fragments left behind, as
+        // they would resurrect the keys removed before this compaction.
+        if log
+            .fragments
+            .first()
+            .is_some_and(|&fragment| fragment < new_gen)
+        {
+            let sync = self.shared.sync_policy != SyncPolicy::Never;
+            write_replaced_before(dir, new_gen, sync)?;
+        } else {
+            match std::fs::remove_file(dir.join(REPLACED_FILE)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    warn!(target: "kvs::engine", error = %err, "failed to remove record of replaced fragments")
+                }
+                _ => {}
+            }
+        }
+        info!(
+            target: "kvs::engine",
+            fragment = active,
+            fragments = active - new_gen + 1,
+            reclaimed,
+            duration_ms = snapshot.start.elapsed().as_secs_f64() * 1000.0,
+            "compaction finished"
+        );
+        Ok(removed)
+    }
+
+    /// Copies the entries written since the compaction's snapshot, at the
+    /// snapshot's `positions` in `output`, into `output`, and writes
+    /// tombstones for the keys removed since.
+    ///
+    /// Must be called with the log lock held, so the index does not change.
+    /// Returns the new positions of the copied entries, by namespace and key,
+    /// along with the space taken up in `output` by entries that are already
+    /// stale.
+    fn copy_changes(
+        &self,
+        output: &mut CompactionOutput,
+        snapshot: &CompactionSnapshot,
+        positions: &[EntryPosition],
+    ) -> Result<(MovedEntries, usize)> {
+        let index = self.shared.read_index();
+        let mut readers = self.readers.borrow_mut();
+        let mut stale = 0;
+        for (((ns, key), old_ep), copy) in snapshot.entries.iter().zip(positions) {
+            match index.get(ns, key) {
+                Some(ep) if ep.fragment == old_ep.fragment && ep.pos == old_ep.pos => {}
+                Some(_) => stale += copy.size,
+                None => {
+                    // The stale copy must not resurrect the key on reopen.
+                    let tombstone = self.shared.encode(&LogEntry::Rm {
+                        ns: ns.clone(),
+                        key: key.clone(),
+                    })?;
+                    let (_, _, size) = output.append(&self.shared, &tombstone)?;
+                    stale += copy.size + size;
+                }
+            }
+        }
+
+        let mut moved = HashMap::new();
+        let copied: HashMap<(&str, &str), &EntryPosition> = snapshot
+            .entries
+            .iter()
+            .map(|((ns, key), ep)| ((ns.as_str(), key.as_str()), ep))
+            .collect();
+        for (ns, key, ep) in index.iter() {
+            if let Some(old_ep) = copied.get(&(ns, key.as_str())) {
+                if ep.fragment == old_ep.fragment && ep.pos == old_ep.pos {
+                    continue;
+                }
+            }
+            let buf = self.read_raw_entry(&mut readers, snapshot.new_gen, ns, key, ep)?;
+            let (fragment, pos, size) = output.append(&self.shared, &buf)?;
+            let new_ep = EntryPosition {
+                fragment,
+                pos,
+                size,
+                ..ep.clone()
+            };
+            moved.insert((ns.to_owned(), key.clone()), new_ep);
+        }
+        Ok((moved, stale))
+    }
+
+    /// Writes a set entry for `key`, skipping writes that would not change
+    /// the stored value.
+    fn apply_set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_locked(&mut self.shared.lock_log(), DEFAULT_NAMESPACE, key, value)
+    }
+
+    /// Like `apply_set`, for a key of namespace `ns` and while holding the
+    /// log lock.
+    fn set_locked(&self, log: &mut LogWriter, ns: &str, key: String, value: String) -> Result<()> {
+        let value_hash = hash_value(value.as_bytes());
+        let current = self.shared.read_index().get(ns, &key).cloned();
+        if self.is_identical_write(
+            log,
+            ns,
+            &key,
+            value.as_bytes(),
+            value_hash,
+            current.as_ref(),
+        )? {
+            return Ok(());
+        }
+
+        let entry = match self.shared.compress(value.as_bytes())? {
+            Some(value) => LogEntry::SetCompressed {
+                ns: ns.to_owned(),
+                key: key.clone(),
+                value,
+            },
+            None => LogEntry::Set {
+                ns: ns.to_owned(),
+                key: key.clone(),
+                value,
+            },
+        };
+        self.write_set(log, ns, key, &entry, value_hash, None)
+    }
+
+    /// Writes a byte value entry for `key`, skipping writes that would not
+    /// change the stored value.
+    fn apply_set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        let mut log = self.shared.lock_log();
+        let value_hash = hash_value(&value);
+        let current = self
+            .shared
+            .read_index()
+            .get(DEFAULT_NAMESPACE, &key)
+            .cloned();
+        if self.is_identical_write(
+            &mut log,
+            DEFAULT_NAMESPACE,
+            &key,
+            &value,
+            value_hash,
+            current.as_ref(),
+        )? {
+            return Ok(());
+        }
+
+        let entry = match self.shared.compress(&value)? {
+            Some(value) => LogEntry::SetCompressed {
+                ns: String::new(),
+                key: key.clone(),
+                value,
+            },
+            None => LogEntry::SetBytes {
+                ns: String::new(),
+                key: key.clone(),
+                value,
+            },
+        };
+        self.write_set(&mut log, DEFAULT_NAMESPACE, key, &entry, value_hash, None)
+    }
+
+    /// Writes a remove entry for `key`.
+    fn apply_remove(&mut self, key: String) -> Result<()> {
+        self.remove_locked(&mut self.shared.lock_log(), DEFAULT_NAMESPACE, key)
+    }
+
+    /// Like `apply_remove`, for a key of namespace `ns` and while holding the
+    /// log lock.
+    fn remove_locked(&self, log: &mut LogWriter, ns: &str, key: String) -> Result<()> {
+        self.shared.evict_expired(log, Some((ns, &key)));
+        let size = match self.shared.read_index().get(ns, &key) {
+            None => return Err(StoreError::NotFound),
+            Some(ep) => ep.size,
+        };
+
+        let entry = LogEntry::Rm {
+            ns: ns.to_owned(),
+            key: key.clone(),
+        };
+        let buf = self.shared.encode(&entry)?;
+        log.writer.write_all(&buf)?;
+        log.write_pos += buf.len() as u64;
+        self.shared.sync_write(log)?;
+
+        let mut index = self.shared.write_index();
+        if ns == DEFAULT_NAMESPACE {
+            self.shared.lock_cache().remove(&key);
+        }
+        index.remove(ns, &key);
+        drop(index);
+        log.unreclaimed_space += size + buf.len();
+        self.compact_if_needed_locked(log)
+    }
+
+    /// Reads the current value of a key while holding the log lock, so it
+    /// can not change before the caller writes.
+    fn read_locked(&self, log: &mut LogWriter, ns: &str, key: &str) -> Result<Option<String>> {
+        self.shared.evict_expired(log, Some((ns, key)));
+        let ep = match self.shared.read_index().get(ns, key) {
+            Some(ep) => ep.clone(),
+            None => return Ok(None),
+        };
+        log.writer.flush()?;
+        Ok(Some(String::from_utf8(self.read_entry(ns, key, &ep)?)?))
+    }
+
+    /// Appends a set entry for `key` of namespace `ns` to the log and points
+    /// the index at it.
+    fn write_set(
+        &self,
+        log: &mut LogWriter,
+        ns: &str,
+        key: String,
+        entry: &LogEntry,
+        value_hash: u64,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let buf = self.shared.encode(entry)?;
+        let pos = log.write_pos;
+        log.writer.write_all(&buf)?;
+        log.write_pos += buf.len() as u64;
+        self.shared.sync_write(log)?;
+
+        let mut ep: EntryPosition = (log.fragment, pos..log.write_pos, value_hash).into();
+        ep.expires_at = expires_at;
+        self.shared.insert_index(log, ns, key, ep);
+        self.compact_if_needed_locked(log)
+    }
+
+    /// Compacts the log once its unreclaimed space exceeds the compaction
+    /// threshold.
+    fn compact_if_needed(&mut self) -> Result<()> {
+        let mut log = self.shared.lock_log();
+        self.compact_if_needed_locked(&mut log)
+    }
+
+    /// Like `compact_if_needed`, while holding the log lock.
+    ///
+    /// With `background_compaction` enabled the compaction is started on a
+    /// background thread instead.
+    fn compact_if_needed_locked(&self, log: &mut LogWriter) -> Result<()> {
+        if log.unreclaimed_space <= self.shared.compaction_threshold {
+            return Ok(());
+        }
+        if self.shared.background_compaction {
+            return self.spawn_compaction();
+        }
+        self.compact_locked(log)
+    }
+
+    /// Starts compacting the store on a background thread, unless a
+    /// background compaction is already running.
+    fn spawn_compaction(&self) -> Result<()> {
+        let mut background = self.shared.lock_background();
+        if background
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+        {
+            return Ok(());
+        }
+
+        let mut store = self.clone();
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || {
+                // A compaction may have run since this one was requested.
+                if store.unreclaimed_space() <= store.compaction_threshold() {
+                    return;
+                }
+                // Failures are logged by the compaction and leave the store
+                // unchanged; the next write retries.
+                let _ = store.compact();
+            })?;
+        *background = Some(handle);
+        Ok(())
+    }
+
+    /// Returns whether writing `value` can be skipped because it already is
+    /// the value of the key's `current` entry and `skip_identical_writes` is
+    /// enabled. Writes replacing an expiring entry are never skipped.
+    fn is_identical_write(
+        &self,
+        log: &mut LogWriter,
+        ns: &str,
+        key: &str,
+        value: &[u8],
+        value_hash: u64,
+        current: Option<&EntryPosition>,
+    ) -> Result<bool> {
+        match current {
+            Some(ep)
+                if self.shared.skip_identical_writes
+                    && ep.expires_at.is_none()
+                    && ep.value_hash == value_hash =>
+            {
+                log.writer.flush()?;
+                Ok(self.read_entry(ns, key, ep)? == value)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Reads the current value of a key from its log fragment, caching it.
+    fn read_value(&self, key: &str) -> Result<Option<Arc<str>>> {
+        self.read_current(DEFAULT_NAMESPACE, key, |store| store.read_flushed(key))
+    }
+
+    /// Reads the current value of a key of namespace `ns` from its log
+    /// fragment using `read`, once expired entries are evicted and buffered
+    /// writes are flushed.
+    fn read_current<T>(
+        &self,
+        ns: &str,
+        key: &str,
+        read: impl FnOnce(&Self) -> Result<Option<T>>,
+    ) -> Result<Option<T>> {
+        let now = now_millis();
+        if self
+            .shared
+            .read_index()
+            .get(ns, key)
+            .is_some_and(|ep| ep.is_expired(now))
+        {
+            self.shared
+                .evict_expired(&mut self.shared.lock_log(), Some((ns, key)));
+            return Ok(None);
+        }
+
+        // Under `SyncPolicy::Never` the entry may still be buffered; hold the
+        // log lock so nothing is buffered between the flush and the read.
+        if self.shared.sync_policy == SyncPolicy::Never {
+            let mut log = self.shared.lock_log();
+            log.writer.flush()?;
+            return read(self);
+        }
+        read(self)
+    }
+
+    /// Reads the current value of a key, which must not be buffered by the
+    /// writer, from its log fragment and caches it.
+    ///
+    /// The index stays locked for the duration of the read, so compaction can
+    /// not move the entry and the value can not be cached after it was
+    /// overwritten. Expiring values are not cached, as the cache can not tell
+    /// when they expire.
+    fn read_flushed(&self, key: &str) -> Result<Option<Arc<str>>> {
+        let index = self.shared.read_index();
+        let ep = match index.get(DEFAULT_NAMESPACE, key) {
+            Some(ep) if !ep.is_expired(now_millis()) => ep,
+            _ => return Ok(None),
+        };
+        let value: Arc<str> = Arc::from(String::from_utf8(self.read_entry(
+            DEFAULT_NAMESPACE,
+            key,
+            ep,
+        )?)?);
+        if ep.expires_at.is_none() {
+            self.shared
+                .lock_cache()
+                .insert(key.to_owned(), value.clone());
+        }
+        Ok(Some(value))
+    }
+
+    /// Reads the bytes of the current value of a key of namespace `ns`,
+    /// which must not be buffered by the writer, from its log fragment.
+    ///
+    /// Byte values are not cached, as the cache only holds strings.
+    fn read_flushed_bytes(&self, ns: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let index = self.shared.read_index();
+        match index.get(ns, key) {
+            Some(ep) if !ep.is_expired(now_millis()) => self.read_entry(ns, key, ep).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the bytes of the value of a key of namespace `ns` from the entry
+    /// at the given position.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreError::Fragment` if the fragment is missing or the entry
+    /// at the position is not the key's value; either means the fragment is
+    /// corrupt or the index is out of sync with it.
+    fn read_entry(&self, ns: &str, key: &str, ep: &EntryPosition) -> Result<Vec<u8>> {
+        let mut readers = self.readers.borrow_mut();
+        let reader = self.reader(&mut readers, ep.fragment).map_err(|err| {
+            StoreError::Fragment(format!(
+                "missing fragment reader {} for entry {}: {}",
+                ep.fragment,
+                describe_key(ns, key),
+                err
+            ))
+        })?;
+        if ep.size > self.shared.max_entry_size {
+            return Err(StoreError::Fragment(format!(
+                "entry of {} in fragment {} at byte offset {} has {} bytes, exceeding the maximum entry size of {} bytes",
+                describe_key(ns, key), ep.fragment, ep.pos, ep.size, self.shared.max_entry_size
+            )));
+        }
+        reader.seek(SeekFrom::Start(ep.pos))?;
+
+        let mut buf = vec![0; ep.size];
+        reader.read_exact(&mut buf[..])?;
+
+        let corrupt = |found: String| {
+            StoreError::Fragment(format!(
+                "expected value of {} in fragment {} at byte offset {}, found {}",
+                describe_key(ns, key),
+                ep.fragment,
+                ep.pos,
+                found
+            ))
+        };
+        match self
+            .shared
+            .codec
+            .decode(&buf[..], self.shared.verify_checksums)
+        {
+            Ok(
+                LogEntry::Set {
+                    ns: found_ns,
+                    key: found,
+                    value,
+                }
+                | LogEntry::SetEx {
+                    ns: found_ns,
+                    key: found,
+                    value,
+                    ..
+                },
+            ) if found_ns == ns && found == key => Ok(value.into_bytes()),
+            Ok(LogEntry::SetBytes {
+                ns: found_ns,
+                key: found,
+                value,
+            }) if found_ns == ns && found == key => Ok(value),
+            Ok(LogEntry::SetCompressed {
+                ns: found_ns,
+                key: found,
+                value,
+            }) if found_ns == ns && found == key => {
+                zstd::bulk::decompress(&value, self.shared.max_entry_size)
+                    .map_err(|err| corrupt(format!("undecompressable value: {}", err)))
+            }
+            Ok(entry) => Err(corrupt(format!("{:?}", entry))),
+            Err(err) => Err(corrupt(err.to_string())),
+        }
+    }
+
+    /// Returns this handle's reader of a fragment, opening it if needed.
+    ///
+    /// Readers of fragments replaced by a compaction are dropped first, and
+    /// other readers are closed as needed to keep within the reader buffer
+    /// budget.
+    fn reader<'a>(
+        &self,
+        readers: &'a mut HashMap<u64, BufReader<File>>,
+        fragment: u64,
+    ) -> std::io::Result<&'a mut BufReader<File>> {
+        let oldest = self.shared.oldest_fragment.load(Ordering::Acquire);
+        readers.retain(|&fragment, _| fragment >= oldest);
+        if let Entry::Vacant(_) = readers.entry(fragment) {
+            let file = File::open(self.shared.dir.join(fragment_filename(fragment)))?;
+            let reader = BufReader::new(file);
+            if let Some(budget) = self.shared.reader_buffer_budget {
+                evict_readers(readers, budget.saturating_sub(reader.capacity()));
+            }
+            readers.insert(fragment, reader);
+        }
+        Ok(readers.get_mut(&fragment).expect("reader was just opened"))
+    }
+
+    /// Returns every key starting with `prefix`, sorted.
+    fn prefix_keys(&self, prefix: &str) -> Vec<String> {
+        self.shared
+            .read_index()
+            .namespace(DEFAULT_NAMESPACE)
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect()
+    }
+
+    /// Reads the values of the given keys, skipping keys without a value.
+    fn read_pairs(&self, keys: Vec<String>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Writes the entries of a compaction's snapshot into new fragment files,
+    /// starting with generation `new_gen`, at their temporary paths.
+    ///
+    /// Runs without any lock, while other handles keep appending to the
+    /// active fragment. That is safe because the snapshot only references
+    /// entries flushed before it was taken, and appends never modify bytes
+    /// already written. Returns the fragments being written along with the
+    /// new position of every entry of the snapshot, in order.
+    fn write_compacted(
+        &self,
+        snapshot: &CompactionSnapshot,
+    ) -> Result<(CompactionOutput, Vec<EntryPosition>)> {
+        let mut readers = self.readers.borrow_mut();
+        let mut output = CompactionOutput::new(&self.shared, snapshot.new_gen)?;
+        let mut positions = Vec::with_capacity(snapshot.entries.len());
+        for ((ns, key), ep) in &snapshot.entries {
+            let buf = self.read_raw_entry(&mut readers, snapshot.new_gen, ns, key, ep)?;
+            let (fragment, pos, size) = output.append(&self.shared, &buf)?;
+            positions.push(EntryPosition {
+                fragment,
+                pos,
+                size,
+                ..ep.clone()
+            });
+        }
+        Ok((output, positions))
+    }
+
+    /// Reads the encoded entry at the given position, to be copied into the
+    /// fragments of generation `new_gen` and later.
+    fn read_raw_entry(
+        &self,
+        readers: &mut HashMap<u64, BufReader<File>>,
+        new_gen: u64,
+        ns: &str,
+        key: &str,
+        ep: &EntryPosition,
+    ) -> Result<Vec<u8>> {
+        let reader = self.reader(readers, ep.fragment).map_err(|err| {
+            StoreError::Fragment(format!(
+                "[Gen({})] missing fragment reader {} for entry {}: {}",
+                new_gen,
+                ep.fragment,
+                describe_key(ns, key),
+                err
+            ))
+        })?;
+        reader.seek(SeekFrom::Start(ep.pos))?;
+
+        let mut buf = vec![0; ep.size];
+        reader.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// Handle to a namespace of a [`KvStore`], created by
+/// [`KvStore::namespace`].
+///
+/// Values read through the handle bypass the store's value cache.
+pub struct NamespaceHandle<'a> {
+    store: &'a mut KvStore,
+    name: String,
+}
+
+impl NamespaceHandle<'_> {
+    /// Returns the name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the value of a key in the namespace.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        let ns = self.name.as_str();
+        let value = self
+            .store
+            .read_current(ns, &key, |store| store.read_flushed_bytes(ns, &key))?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Sets the value of a key in the namespace.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let mut log = self.store.shared.lock_log();
+        self.store.set_locked(&mut log, &self.name, key, value)
+    }
+
+    /// Removes a key from the namespace.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreError::NotFound` if the namespace does not hold the key,
+    /// even if another namespace does.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let mut log = self.store.shared.lock_log();
+        self.store.remove_locked(&mut log, &self.name, key)
+    }
+}
+
+/// Live entries of the store when a compaction started, to be copied into
+/// the new fragments.
+struct CompactionSnapshot {
+    /// Generation of the first new fragment
+    new_gen: u64,
+    /// Entries to copy by namespace and key, in [`compaction_order`]
+    entries: Vec<((String, String), EntryPosition)>,
+    start: Instant,
+}
+
+/// Fragments written by a compaction.
+struct CompactionOutput {
+    fragments: Vec<FragmentWriter>,
+    /// Position of the next entry in the last fragment
+    pos: u64,
+}
+
+impl CompactionOutput {
+    /// Creates the first fragment, of generation `new_gen`, at its temporary
+    /// path.
+    fn new(shared: &SharedStore, new_gen: u64) -> Result<Self> {
+        Ok(Self {
+            fragments: vec![(new_gen, Self::new_writer(shared, new_gen)?)],
+            pos: shared.codec.header().len() as u64,
+        })
+    }
+
+    fn new_writer(shared: &SharedStore, fragment: u64) -> Result<BufWriter<File>> {
+        let path = temp_fragment_path(&shared.dir, fragment);
+        Ok(BufWriter::new(new_fragment(&path, shared.codec)?))
+    }
+
+    /// Appends an encoded entry, returning its fragment, position and size.
+    ///
+    /// A fragment is started for the next generation whenever the current one
+    /// would exceed `max_fragment_bytes`.
+    fn append(&mut self, shared: &SharedStore, buf: &[u8]) -> Result<(u64, u64, usize)> {
+        let header_len = shared.codec.header().len() as u64;
+        // JSON entries may carry delimiters from either mode; rewrite them
+        // in the configured one.
+        let entry = match shared.codec {
+            Codec::Json => buf.trim_ascii(),
+            Codec::Binary => buf,
+        };
+        let size = entry.len() + usize::from(shared.newline_delimited);
+        if let Some(max) = shared.max_fragment_bytes {
+            // A single entry larger than the limit gets a fragment of its
+            // own.
+            if self.pos > header_len && self.pos + size as u64 > max {
+                let fragment = self
+                    .fragments
+                    .last()
+                    .expect("compaction writes a fragment")
+                    .0
+                    + 1;
+                self.fragments
+                    .push((fragment, Self::new_writer(shared, fragment)?));
+                self.pos = header_len;
+            }
+        }
+
+        let (fragment, writer) = self
+            .fragments
+            .last_mut()
+            .expect("compaction writes a fragment");
+        writer.write_all(entry)?;
+        if shared.newline_delimited {
+            writer.write_all(b"\n")?;
+        }
+        let pos = self.pos;
+        self.pos += size as u64;
+        Ok((*fragment, pos, size))
+    }
+
+    /// Flushes the fragments and syncs them to disk, unless the sync policy
+    /// is `Never`.
+    fn sync(&mut self, shared: &SharedStore) -> Result<()> {
+        for (_, writer) in self.fragments.iter_mut() {
+            writer.flush()?;
+            if shared.sync_policy != SyncPolicy::Never {
+                writer.get_ref().sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SharedStore {
+    /// Locks the index for reading.
+    ///
+    /// A panic while holding a lock leaves the store's state consistent, as
+    /// every update is applied only once it can no longer fail; poisoned locks
+    /// are therefore recovered.
+    fn read_index(&self) -> RwLockReadGuard<'_, Index> {
+        self.index.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the index for writing.
+    fn write_index(&self) -> RwLockWriteGuard<'_, Index> {
+        self.index.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the writing end of the log.
+    fn lock_log(&self) -> MutexGuard<'_, LogWriter> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Compresses a value according to the compression options.
+    ///
+    /// Returns `None` if the value should be written uncompressed, because it
+    /// is too small or does not compress.
+    fn compress(&self, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let level = match self.compression {
+            Compression::Zstd { level } if value.len() >= self.min_compress_bytes => level,
+            _ => return Ok(None),
+        };
+        let compressed = zstd::bulk::compress(value, level)?;
+        Ok((compressed.len() < value.len()).then_some(compressed))
+    }
+
+    /// Locks out other compactions.
+    fn lock_compaction(&self) -> MutexGuard<'_, ()> {
+        self.compaction
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the handle of the background compaction.
+    fn lock_background(&self) -> MutexGuard<'_, Option<JoinHandle<()>>> {
+        self.background
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the value cache.
+    fn lock_cache(&self) -> MutexGuard<'_, ValueCache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Drops expired entries from the index, counting their space as
+    /// unreclaimed.
+    ///
+    /// Only the given key, by namespace and key, is checked if set, otherwise
+    /// every key is.
+    fn evict_expired(&self, log: &mut LogWriter, key: Option<(&str, &str)>) {
+        let now = now_millis();
+        let mut index = self.write_index();
+        let expired: Vec<(String, String)> = match key {
+            Some((ns, key)) => index
+                .get(ns, key)
+                .filter(|ep| ep.is_expired(now))
+                .map(|_| (ns.to_owned(), key.to_owned()))
+                .into_iter()
+                .collect(),
+            None => index
+                .iter()
+                .filter(|(_, _, ep)| ep.is_expired(now))
+                .map(|(ns, key, _)| (ns.to_owned(), key.clone()))
+                .collect(),
+        };
+        for (ns, key) in expired {
+            if let Some(ep) = index.remove(&ns, &key) {
+                log.unreclaimed_space += ep.size;
+            }
+        }
+    }
+
+    /// Points the index at the new entry of a key of namespace `ns`,
+    /// invalidating its cached value.
+    fn insert_index(&self, log: &mut LogWriter, ns: &str, key: String, ep: EntryPosition) {
+        let mut index = self.write_index();
+        if ns == DEFAULT_NAMESPACE {
+            self.lock_cache().remove(&key);
+        }
+        if let Some(prev) = index.insert(ns, key, ep) {
+            log.unreclaimed_space += prev.size;
+        }
+    }
+
+    /// Makes a write durable as required by the sync policy.
+    fn sync_write(&self, log: &mut LogWriter) -> Result<()> {
+        match self.sync_policy {
+            SyncPolicy::Always => {
+                log.writer.flush()?;
+                Ok(log.writer.get_ref().sync_all()?)
+            }
+            SyncPolicy::OnFlush => Ok(log.writer.flush()?),
+            SyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Serializes a log entry, including its delimiter if configured.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreError::EntryTooLarge` if the entry exceeds the maximum
+    /// entry size; it could not be read back.
+    fn encode(&self, entry: &LogEntry) -> Result<Vec<u8>> {
+        let mut buf = self.codec.encode(entry)?;
+        if self.newline_delimited {
+            buf.push(b'\n');
+        }
+        if buf.len() > self.max_entry_size {
+            return Err(StoreError::EntryTooLarge {
+                size: buf.len(),
+                max: self.max_entry_size,
+            });
+        }
+        Ok(buf)
+    }
+}
+
+impl KvEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let trace = OpTrace::start("set", &key);
+        let bytes = value.len();
+        trace.finish(self.apply_set(key, value), |_| bytes)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        let trace = OpTrace::start("remove", &key);
+        trace.finish(self.apply_remove(key), |_| 0)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let keys = self.prefix_keys(prefix);
+        self.read_pairs(keys)
+    }
+
+    fn range(&mut self, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<(String, String)>> {
+        // BTreeMap::range panics on inverted bounds; they select no keys.
+        if let (
+            Bound::Included(first) | Bound::Excluded(first),
+            Bound::Included(last) | Bound::Excluded(last),
+        ) = (start, end)
+        {
+            let both_excluded = matches!((start, end), (Bound::Excluded(_), Bound::Excluded(_)));
+            if first > last || (first == last && both_excluded) {
+                return Ok(Vec::new());
+            }
+        }
+
+        let keys: Vec<String> = self
+            .shared
+            .read_index()
+            .namespace(DEFAULT_NAMESPACE)
+            .range::<str, _>((start, end))
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.read_pairs(keys)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        KvStore::set_with_ttl(self, key, value, ttl)
+    }
+
+    // Clones share the store, so `&mut self` does not keep them from writing
+    // between the check and the write; both happen under the log lock.
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        let mut log = self.shared.lock_log();
+        self.shared
+            .evict_expired(&mut log, Some((DEFAULT_NAMESPACE, &key)));
+        if self
+            .shared
+            .read_index()
+            .get(DEFAULT_NAMESPACE, &key)
+            .is_some()
+        {
+            return Ok(false);
+        }
+        self.set_locked(&mut log, DEFAULT_NAMESPACE, key, value)?;
+        Ok(true)
+    }
+
+    fn compare_and_swap(&mut self, key: String, expected: String, new: String) -> Result<bool> {
+        let mut log = self.shared.lock_log();
+        if self
+            .read_locked(&mut log, DEFAULT_NAMESPACE, &key)?
+            .as_ref()
+            != Some(&expected)
+        {
+            return Ok(false);
+        }
+        self.set_locked(&mut log, DEFAULT_NAMESPACE, key, new)?;
+        Ok(true)
+    }
+
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        KvStore::remove_if(self, key, expected)
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities {
+            scan: true,
+            ttl: true,
+            cas: true,
+            compaction: true,
+            batch: true,
+        }
+    }
+
+    fn stats(&mut self) -> EngineStats {
+        EngineStats {
+            live_keys: self.len() as u64,
+            compactions: self.compactions(),
+            reclaimed_space: self.reclaimed_space(),
+        }
+    }
+}
+
+/// Returns the index entries in the order compaction writes them.
+fn compaction_order<K: Ord, T>(
+    entries: impl Iterator<Item = (K, T)>,
+    ordered: bool,
+) -> Vec<(K, T)> {
+    let mut entries: Vec<(K, T)> = entries.collect();
+    if ordered {
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    entries
+}
+
+/// Parses the generation of a fragment from its file name.
+fn fragment_generation(path: &Path) -> Result<u64> {
+    path.file_name()
+        .and_then(|s| s.to_str())
+        .ok_or(StoreError::Fragment("invalid fragment file name".into()))?
+        .split('.')
+        .next()
+        .ok_or(StoreError::Fragment("invalid fragment file name".into()))?
+        .parse::<u64>()
+        .map_err(|_| StoreError::Fragment("invalid fragment number".into()))
+}
+
+/// Detects the codec of a store from the start of its oldest non-empty
+/// fragment.
+///
+/// Returns `None` if no fragment holds any data or its encoding is not
+/// recognized.
+fn detect_codec(paths: &[(u64, PathBuf)]) -> Result<Option<Codec>> {
+    for (_, path) in paths {
+        let mut start = Vec::new();
+        match File::open(path) {
+            Ok(file) => file
+                .take(BINARY_HEADER.len() as u64)
+                .read_to_end(&mut start)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if !start.is_empty() {
+            return Ok(Codec::detect(&start));
+        }
+    }
+    Ok(None)
+}
+
+/// Loads the given fragments, in order, into the index.
+///
+/// Fragments that disappeared since they were listed, e.g. removed by a
+/// concurrent compaction, are skipped. Returns a reader for every loaded
+/// fragment and the total size of unreclaimed space.
+fn load_fragments(
+    paths: Vec<(u64, PathBuf)>,
+    codec: Codec,
+    max_entry_size: usize,
+    index: &mut Index,
+) -> Result<(HashMap<u64, BufReader<File>>, usize)> {
+    let mut readers = HashMap::new();
+    let mut unreclaimed_space = 0;
+
+    let active = paths.last().map(|(fragment, _)| *fragment);
+    for (fragment, path) in paths {
+        let is_active = Some(fragment) == active;
+        match load_fragment(fragment, path, codec, max_entry_size, is_active, index) {
+            Ok((c_space, reader)) => {
+                unreclaimed_space += c_space;
+                readers.insert(fragment, reader);
+            }
+            Err(StoreError::Io(err)) if err.kind() == std::io::ErrorKind::NotFound => {
+                warn!(
+                    target: "kvs::engine",
+                    fragment,
+                    "skipping fragment that disappeared while loading"
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok((readers, unreclaimed_space))
+}
+
+/// Loads the Key-Value store log fragment at the given path.
+///
+/// The process entails indexing the entries at the given path. It returns the
+/// size of unreclaimed space and a `BufReader` for the fragment.
+///
+/// A trailing entry cut short, as left behind by a crash while writing it, is
+/// truncated away if the fragment is the `active` one; anywhere else the log
+/// continues past it, so it is reported as corruption. So is an entry cut
+/// short by a corrupt length, which complete entries still follow.
+fn load_fragment(
+    fragment: u64,
+    path: PathBuf,
+    codec: Codec,
+    max_entry_size: usize,
+    active: bool,
+    index: &mut Index,
+) -> Result<(usize, BufReader<File>)> {
+    let mut unreclaimed_space = 0;
+
+    let log = OpenOptions::new().read(true).open(&path)?;
+    let mut reader = BufReader::new(log);
+    reader.seek(SeekFrom::Start(0))?;
+
+    let now = now_millis();
+    let truncated = codec.read_entries(fragment, &mut reader, max_entry_size, |entry, range| {
+        if let Some(prev_ep) = match entry {
+            LogEntry::Set { ns, key, value } => index.insert(
+                &ns,
+                key,
+                (fragment, range, hash_value(value.as_bytes())).into(),
+            ),
+            // Tombstones are only needed until a compaction drops the entries
+            // they shadow.
+            LogEntry::Rm { ref ns, ref key } => {
+                unreclaimed_space += (range.end - range.start) as usize;
+                index.remove(ns, key)
+            }
+            // An expired entry removes the key, like a removal would have.
+            LogEntry::SetEx {
+                ref ns,
+                ref key,
+                expires_at,
+                ..
+            } if expires_at <= now => {
+                unreclaimed_space += (range.end - range.start) as usize;
+                index.remove(ns, key)
+            }
+            LogEntry::SetEx {
+                ns,
+                key,
+                value,
+                expires_at,
+            } => {
+                let mut ep: EntryPosition = (fragment, range, hash_value(value.as_bytes())).into();
+                ep.expires_at = Some(expires_at);
+                index.insert(&ns, key, ep)
+            }
+            LogEntry::SetBytes { ns, key, value } => {
+                index.insert(&ns, key, (fragment, range, hash_value(&value)).into())
+            }
+            // Values are hashed uncompressed, as they are when written. An
+            // undecompressable value is reported once it is read.
+            LogEntry::SetCompressed { ns, key, value } => {
+                let value_hash = zstd::bulk::decompress(&value, max_entry_size)
+                    .map_or(0, |value| hash_value(&value));
+                index.insert(&ns, key, (fragment, range, value_hash).into())
+            }
+        } {
+            unreclaimed_space += prev_ep.size;
+        }
+    })?;
+
+    match truncated {
+        Some(end) if active => {
+            let mut tail = Vec::new();
+            reader.seek(SeekFrom::Start(end))?;
+            reader
+                .by_ref()
+                .take((max_entry_size as u64).saturating_add(1))
+                .read_to_end(&mut tail)?;
+            if !codec.is_torn_tail(&tail, max_entry_size) {
+                return Err(StoreError::Fragment(format!(
+                    "fragment {} has a corrupt entry at byte offset {}",
+                    fragment, end
+                )));
+            }
+            let file = OpenOptions::new().write(true).open(&path)?;
+            warn!(
+                target: "kvs::engine",
+                fragment,
+                discarded = file.metadata()?.len() - end,
+                "truncating partially written entry at the end of the active fragment"
+            );
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+        Some(end) => {
+            return Err(StoreError::Fragment(format!(
+                "fragment {} ends with a partially written entry at byte offset {}",
+                fragment, end
+            )))
+        }
+        None => {}
+    }
+    Ok((unreclaimed_space, reader))
+}
+
+/// Opens the latest fragment for writing.
+///
+/// If the fragment is unexpectedly gone, e.g. it was removed after the store
+/// directory was listed, a fresh fragment with the next generation is created
+/// instead. Returns the generation of the fragment opened for writing.
+fn open_active_fragment(
+    dir: &Path,
+    fragment: u64,
+    codec: Codec,
+    fragment_readers: &mut HashMap<u64, BufReader<File>>,
+) -> Result<(u64, File)> {
+    let path = dir.join(fragment_filename(fragment));
+    match OpenOptions::new().write(true).open(&path) {
+        Ok(mut file) => {
+            // A fragment that was never written to is still missing its header.
+            if file.metadata()?.len() == 0 {
+                file.write_all(codec.header())?;
+            }
+            Ok((fragment, file))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                target: "kvs::engine",
+                fragment,
+                "active fragment is missing; rolling over to a new fragment"
+            );
+            let fragment = fragment + 1;
+            let file = new_fragment(&dir.join(fragment_filename(fragment)), codec)?;
+            fragment_readers.insert(fragment, BufReader::new(file.try_clone()?));
+            Ok((fragment, file))
+        }
+        Err(err) => Err(StoreError::Fragment(format!(
+            "unable to open active fragment {} for writing: {}",
+            path.display(),
+            err
+        ))),
+    }
+}
+
+/// Creates a new fragment file starting with the codec's header. If file
+/// already exists it is truncated.
+fn new_fragment(path: &Path, codec: Codec) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(path)?;
+    file.write_all(codec.header())?;
+    Ok(file)
+}
+
+/// Returns the generation below which fragments were replaced by a
+/// compaction, or 0 if no replaced fragment was left behind.
+fn read_replaced_before(dir: &Path) -> Result<u64> {
+    match std::fs::read_to_string(dir.join(REPLACED_FILE)) {
+        Ok(generation) => generation.trim().parse().map_err(|_| {
+            StoreError::Fragment(format!(
+                "invalid generation {:?} in {}",
+                generation, REPLACED_FILE
+            ))
+        }),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Records that the fragments below `generation` were replaced by a
+/// compaction, replacing the previous record atomically.
+fn write_replaced_before(dir: &Path, generation: u64, sync: bool) -> Result<()> {
+    let temp = dir.join(format!("{}.tmp", REPLACED_FILE));
+    let mut file = File::create(&temp)?;
+    file.write_all(generation.to_string().as_bytes())?;
+    if sync {
+        file.sync_all()?;
+    }
+    std::fs::rename(temp, dir.join(REPLACED_FILE))?;
+    Ok(())
+}
+
+/// Moves a fragment replaced by a compaction into the stores trash.
+fn trash_fragment(dir: &Path, fragment: u64) -> Result<()> {
+    let trash = dir.join(TRASH_DIR);
+    std::fs::create_dir_all(&trash)?;
+
+    let path = trash.join(fragment_filename(fragment));
+    std::fs::rename(dir.join(fragment_filename(fragment)), &path)?;
+    // The retention period starts when the fragment is trashed.
+    OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+/// Deletes trashed fragments that have been retained for at least `retention`.
+fn purge_trash(dir: &Path, retention: Duration) -> Result<()> {
+    let trash = dir.join(TRASH_DIR);
+    if !trash.exists() {
+        return Ok(());
+    }
+
+    for entry in trash.read_dir()? {
+        let path = entry?.path();
+        let age = path.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age >= retention {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Traces a single store operation.
+///
+/// The operation runs inside a span recording its name and key; finishing it
+/// emits an event with the number of value bytes involved and how long it
+/// took. Without a subscriber interested in debug events this costs little
+/// more than reading the clock.
+struct OpTrace {
+    _span: EnteredSpan,
+    start: Instant,
+}
+
+impl OpTrace {
+    fn start(op: &'static str, key: &str) -> Self {
+        Self {
+            _span: debug_span!(target: "kvs::engine", "op", op, key).entered(),
+            start: Instant::now(),
+        }
+    }
+
+    fn finish<T>(self, result: Result<T>, bytes: impl FnOnce(&T) -> usize) -> Result<T> {
+        let duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        match &result {
+            Ok(value) => debug!(
+                target: "kvs::engine",
+                bytes = bytes(value),
+                duration_ms,
+                "operation finished"
+            ),
+            Err(err) => debug!(
+                target: "kvs::engine",
+                error = %err,
+                duration_ms,
+                "operation failed"
+            ),
+        }
+        result
+    }
+}
+
+/// Returns the memory in bytes taken up by the buffers of `readers`.
+fn reader_buffer_memory(readers: &HashMap<u64, BufReader<File>>) -> usize {
+    readers.values().map(BufReader::capacity).sum()
+}
+
+/// Closes readers, oldest fragments first, until their buffers take up at
+/// most `budget` bytes.
+fn evict_readers(readers: &mut HashMap<u64, BufReader<File>>, budget: usize) {
+    while reader_buffer_memory(readers) > budget {
+        let oldest = *readers.keys().min().expect("readers use memory");
+        readers.remove(&oldest);
+    }
+}
+
+/// Returns the current unix time in milliseconds.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Hashes a value for cheap equality checks against the index.
+///
+/// Values are hashed as raw bytes, so string and byte values with the same
+/// contents hash alike.
+fn hash_value(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(value);
+    hasher.finish()
+}
+
+/// Names a key in messages, along with its namespace unless it is the
+/// default one.
+fn describe_key(ns: &str, key: &str) -> String {
+    match ns {
+        DEFAULT_NAMESPACE => key.to_owned(),
+        ns => format!("{} in namespace {}", key, ns),
+    }
+}
+
+fn fragment_filename(fragment: u64) -> String {
+    format!("{}.{}", fragment, LOG_EXTENSION)
+}
+
+/// Returns the path a fragment is written to by compaction before it is
+/// renamed into place.
+fn temp_fragment_path(dir: &Path, fragment: u64) -> PathBuf {
+    dir.join(format!("{}.tmp", fragment_filename(fragment)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{atomic::AtomicBool, Mutex};
+    use tempfile::TempDir;
+    use walkdir::WalkDir;
+
+    /// Writer capturing formatted tracing output.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    // Buffered writes should be persisted by flush and close.
+    #[test]
+    fn flush_and_close() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .sync_policy(SyncPolicy::Never)
+            .open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(
+            KvStore::open(temp_dir.path())?.get("key1".to_owned())?,
+            None
+        );
+
+        store.flush()?;
+        assert_eq!(
+            KvStore::open(temp_dir.path())?.get("key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.close()?;
+        assert_eq!(
+            KvStore::open(temp_dir.path())?.get("key2".to_owned())?,
+            Some("value2".to_owned())
+        );
+        Ok(())
+    }
+
+    // Byte values should round trip, including bytes that are not valid
+    // UTF-8, with either codec.
+    #[test]
+    fn byte_values() -> Result<()> {
+        for codec in [Codec::Binary, Codec::Json] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = KvStoreOptions {
+                codec: Some(codec),
+                ..Default::default()
+            };
+            let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+            let value = vec![b'a', 0, 0xff, 0xc3, 0x28, 0];
+            store.set_bytes("key1".to_owned(), value.clone())?;
+            store.set_bytes("key2".to_owned(), b"value2".to_vec())?;
+            store.set("key3".to_owned(), "value3".to_owned())?;
+
+            assert_eq!(store.get_bytes("key1".to_owned())?, Some(value.clone()));
+            assert!(matches!(
+                store.get("key1".to_owned()),
+                Err(StoreError::Utf8(_))
+            ));
+            assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            assert_eq!(
+                store.get_bytes("key3".to_owned())?,
+                Some(b"value3".to_vec())
+            );
+            assert_eq!(store.get_bytes("key4".to_owned())?, None);
+
+            // Byte values survive reopening and compaction.
+            drop(store);
+            let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+            store.compact()?;
+            assert_eq!(store.get_bytes("key1".to_owned())?, Some(value));
+            store.remove("key1".to_owned())?;
+            assert_eq!(store.get_bytes("key1".to_owned())?, None);
+        }
+        Ok(())
+    }
+
+    // Should get previously stored value.
+    #[test]
+    fn get_stored_value() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
+
+    // Should overwrite existent value.
+    #[test]
+    fn overwrite_value() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+        Ok(())
+    }
+
+    // Should get `None` when getting a non-existent key.
+    #[test]
+    fn get_non_existent_value() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_non_existent_key() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(store.remove("key1".to_owned()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_idempotent() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        assert!(store.remove_idempotent("key1".to_owned())?);
+        assert!(!store.remove_idempotent("key1".to_owned())?);
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(StoreError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_key() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(store.remove("key1".to_owned()).is_ok());
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_if() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        assert!(!store.remove_if("key1".to_owned(), "value2".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        assert!(store.remove_if("key1".to_owned(), "value1".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        // Missing keys are not an error.
+        assert!(!store.remove_if("key1".to_owned(), "value1".to_owned())?);
+        Ok(())
+    }
+
+    // Conditional writes of clones on other threads should never both
+    // succeed against the same value.
+    #[test]
+    fn concurrent_conditional_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .sync_policy(SyncPolicy::Never)
+            .open(temp_dir.path())?;
+        store.set("count".to_owned(), "0".to_owned())?;
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut store = store.clone();
+                std::thread::spawn(move || -> Result<usize> {
+                    let mut claimed = 0;
+                    for key_id in 0..2000 {
+                        if KvEngine::set_nx(&mut store, format!("key{}", key_id), "1".to_owned())? {
+                            claimed += 1;
+                        }
+                        loop {
+                            let count = store.get("count".to_owned())?.unwrap();
+                            let next = count.parse::<u64>().unwrap() + 1;
+                            if store.compare_and_swap(
+                                "count".to_owned(),
+                                count,
+                                next.to_string(),
+                            )? {
+                                break;
+                            }
+                        }
+                    }
+                    Ok(claimed)
+                })
+            })
+            .collect();
+        let mut claimed = 0;
+        for writer in writers {
+            claimed += writer.join().expect("writer thread panicked")?;
+        }
+        assert_eq!(claimed, 2000);
+        assert_eq!(store.get("count".to_owned())?, Some("8000".to_owned()));
+        Ok(())
+    }
+
+    // A clone's write between the comparison and the removal should never be
+    // removed along with the value it replaced.
+    #[test]
+    fn concurrent_remove_if() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .sync_policy(SyncPolicy::Never)
+            .open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let removers: Vec<_> = (0..4)
+            .map(|_| {
+                let mut remover = store.clone();
+                let done = Arc::clone(&done);
+                std::thread::spawn(move || -> Result<()> {
+                    while !done.load(Ordering::Acquire) {
+                        remover.remove_if("key1".to_owned(), "value1".to_owned())?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for _ in 0..20_000 {
+            // Only "value1" is ever removed, so "value2" is always still set.
+            assert_eq!(
+                store.set_returning("key1".to_owned(), "value1".to_owned())?,
+                Some("value2".to_owned())
+            );
+            store.set("key1".to_owned(), "value2".to_owned())?;
+        }
+        done.store(true, Ordering::Release);
+        for remover in removers {
+            remover.join().expect("remover thread panicked")?;
+        }
+        Ok(())
+    }
+
+    // Set should be able to return the value it overwrote.
+    #[test]
+    fn set_returning() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .sync_policy(SyncPolicy::Never)
+            .open(temp_dir.path())?;
+
+        assert_eq!(
+            store.set_returning("key1".to_owned(), "value1".to_owned())?,
+            None
+        );
+        // The previous value is still buffered by the writer.
+        assert_eq!(
+            store.set_returning("key1".to_owned(), "value2".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        store.set_bytes("key2".to_owned(), vec![0xff])?;
+        assert!(matches!(
+            store.set_returning("key2".to_owned(), "value3".to_owned()),
+            Err(StoreError::Utf8(_))
+        ));
+        assert_eq!(store.get_bytes("key2".to_owned())?, Some(vec![0xff]));
+        Ok(())
+    }
+
+    // Remove should be able to return the value it removed.
+    #[test]
+    fn remove_returning() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        assert_eq!(store.remove_returning("key1".to_owned())?, "value1");
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert!(matches!(
+            store.remove_returning("key1".to_owned()),
+            Err(StoreError::NotFound)
+        ));
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    }
+
+    // Store should be compacted into a single fragment after sync.
+    #[test]
+    fn sync_after_compaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+
+        let value = "v".repeat(10_000);
+        for _ in 0..150 {
+            store.set("key1".to_owned(), value.clone())?;
+        }
+        store.sync()?;
+
+        assert!(store.active_fragment() > 0);
+        assert!(store.unreclaimed_space() < COMPACTION_THRESHOLD);
+        let fragments = std::fs::read_dir(temp_dir.path())?.count();
+        assert_eq!(fragments, 1);
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(value));
+        Ok(())
+    }
+
+    // Fresh store should start writing at the configured generation.
+    #[test]
+    fn initial_fragment() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            initial_fragment: 100,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(temp_dir.path().join("100.kv").exists());
+
+        drop(store);
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    // Existing fragments older than the initial generation should be rejected.
+    #[test]
+    fn initial_fragment_collision() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        let options = KvStoreOptions {
+            initial_fragment: 100,
+            ..Default::default()
+        };
+        assert!(matches!(
+            KvStore::open_with_options(temp_dir.path(), options),
+            Err(StoreError::Fragment(_))
+        ));
+        Ok(())
+    }
+
+    // A failed compaction should leave the store untouched and usable.
+    #[test]
+    fn failed_compaction_is_transactional() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key0".to_owned(), "value0".to_owned())?;
+
+        // Block the rename of the compacted fragment.
+        let blocker = temp_dir.path().join(fragment_filename(1));
+        std::fs::create_dir(&blocker)?;
+
+        let value = "v".repeat(10_000);
+        let res = (0..150).try_for_each(|_| store.set("key1".to_owned(), value.clone()));
+        assert!(res.is_err());
+
+        assert_eq!(store.active_fragment(), 0);
+        assert!(store.unreclaimed_space() > COMPACTION_THRESHOLD);
+        assert_eq!(store.fragment_count(), 1);
+        assert!(store
+            .shared
+            .read_index()
+            .namespace(DEFAULT_NAMESPACE)
+            .values()
+            .all(|ep| ep.fragment == 0));
+        assert!(!temp_dir.path().join("1.kv.tmp").exists());
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+
+        // Compaction succeeds once the blocker is gone.
+        std::fs::remove_dir(&blocker)?;
+        store.set("key1".to_owned(), value.clone())?;
+        assert_eq!(store.active_fragment(), 1);
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some(value));
+        Ok(())
+    }
+
+    // Shared gets of a cached key should return the same allocation.
+    #[test]
+    fn get_shared() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            value_cache_capacity: 1024,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let first = store.get_shared("key1".to_owned())?.unwrap();
+        let second = store.get_shared("key1".to_owned())?.unwrap();
+        assert_eq!(&*first, "value1");
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(store.get_shared("key2".to_owned())?, None);
+
+        // Overwriting the key should not return the stale allocation.
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        let third = store.get_shared("key1".to_owned())?.unwrap();
+        assert_eq!(&*third, "value2");
+        assert_eq!(&*first, "value1");
+        Ok(())
+    }
+
+    // Corrupt entries and missing fragments should fail reads, not panic.
+    #[test]
+    fn get_corrupt_entry() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        let ep = store.shared.read_index().namespace(DEFAULT_NAMESPACE)["key1"].clone();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(temp_dir.path().join(fragment_filename(0)))?;
+        file.seek(SeekFrom::Start(ep.pos))?;
+        file.write_all(&vec![0xff; ep.size])?;
+        drop(file);
+
+        assert!(matches!(
+            store.get("key1".to_owned()),
+            Err(StoreError::Fragment(_))
+        ));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        store.readers.borrow_mut().clear();
+        std::fs::remove_file(temp_dir.path().join(fragment_filename(0)))?;
+        assert!(matches!(
+            store.get("key2".to_owned()),
+            Err(StoreError::Fragment(_))
+        ));
+        Ok(())
+    }
+
+    // Entries declaring more than the maximum entry size should be reported
+    // as corruption instead of being allocated.
+    #[test]
+    fn oversized_entries() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut fragment = Codec::Binary.header().to_vec();
+        fragment.push(0);
+        fragment.extend_from_slice(&u32::MAX.to_le_bytes());
+        fragment.extend_from_slice(b"key1");
+        std::fs::write(temp_dir.path().join(fragment_filename(0)), fragment)?;
+        assert!(matches!(
+            KvStore::open(temp_dir.path()),
+            Err(StoreError::Fragment(msg)) if msg.contains("maximum entry size")
+        ));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store
+            .shared
+            .write_index()
+            .get_mut(DEFAULT_NAMESPACE, "key1")
+            .unwrap()
+            .size = usize::MAX;
+        assert!(matches!(
+            store.get("key1".to_owned()),
+            Err(StoreError::Fragment(msg)) if msg.contains("maximum entry size")
+        ));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Writes larger than the maximum entry size should be rejected.
+    #[test]
+    fn max_entry_size_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .max_entry_size(64)
+            .open(temp_dir.path())?;
+        assert!(matches!(
+            store.set("key1".to_owned(), "v".repeat(64)),
+            Err(StoreError::EntryTooLarge { max: 64, .. })
+        ));
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        drop(store);
+        let store = KvStore::builder()
+            .max_entry_size(64)
+            .open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    // Gets of prefetched keys should be served from the value cache.
+    #[test]
+    fn prefetch() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .value_cache_capacity(1024)
+            .open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set_with_ttl(
+            "key10".to_owned(),
+            "value10".to_owned(),
+            Duration::from_secs(60),
+        )?;
+
+        let keys: Vec<String> = (0..12)
+            .rev()
+            .map(|key_id| format!("key{}", key_id))
+            .collect();
+        store.prefetch(&keys)?;
+        assert_eq!(store.cache_stats(), (0, 0));
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        assert_eq!(store.cache_stats(), (10, 0));
+        Ok(())
+    }
+
+    // Flipped bytes should be detected by the entry checksums.
+    #[test]
+    fn checksum_mismatch() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key0".to_owned(), "value0".to_owned())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let ep = store.shared.read_index().namespace(DEFAULT_NAMESPACE)["key1"].clone();
+        drop(store);
+
+        let path = temp_dir.path().join(fragment_filename(0));
+        let mut fragment = std::fs::read(&path)?;
+        let value_pos = ep.pos as usize + ep.size - 4 - "value1".len();
+        fragment[value_pos] ^= 0x20;
+        std::fs::write(&path, fragment)?;
+
+        let offset = format!("byte offset {}", ep.pos);
+        assert!(matches!(
+            KvStore::open(temp_dir.path()),
+            Err(StoreError::Fragment(msg)) if msg.contains("checksum") && msg.contains(&offset)
+        ));
+        Ok(())
+    }
+
+    // Gets should verify checksums unless disabled.
+    #[test]
+    fn verify_checksums() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let ep = store.shared.read_index().namespace(DEFAULT_NAMESPACE)["key1"].clone();
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(temp_dir.path().join(fragment_filename(0)))?;
+        file.seek(SeekFrom::Start(ep.pos + ep.size as u64 - 4 - 1))?;
+        file.write_all(b"X")?;
+        drop(file);
+        assert!(matches!(
+            store.get("key1".to_owned()),
+            Err(StoreError::Fragment(msg)) if msg.contains("checksum")
+        ));
+
+        Arc::get_mut(&mut store.shared).unwrap().verify_checksums = false;
+        assert_eq!(store.get("key1".to_owned())?, Some("valueX".to_owned()));
+        Ok(())
+    }
+
+    // A lenient scan should return every readable pair and report the rest.
+    #[test]
+    fn scan_lenient() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set("other".to_owned(), "value".to_owned())?;
+
+        let ep = store.shared.read_index().namespace(DEFAULT_NAMESPACE)["key4"].clone();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(temp_dir.path().join(fragment_filename(0)))?;
+        file.seek(SeekFrom::Start(ep.pos))?;
+        file.write_all(&vec![0xff; ep.size])?;
+        drop(file);
+
+        assert!(store.scan("key").is_err());
+        let (pairs, errors) = store.scan_lenient("key");
+        let expected: Vec<_> = (0..10)
+            .filter(|&key_id| key_id != 4)
+            .map(|key_id| (format!("key{}", key_id), format!("value{}", key_id)))
+            .collect();
+        assert_eq!(pairs, expected);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "key4");
+        assert!(matches!(errors[0].1, StoreError::Fragment(_)));
+        Ok(())
+    }
+
+    // Hot keys should be served from the value cache instead of disk.
+    #[test]
+    fn value_cache_skewed_reads() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            value_cache_capacity: 1_000,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+
+        // 90% of the reads hit the same 5 keys.
+        for iter in 0..1000 {
+            let key_id = if iter % 10 == 0 { iter % 100 } else { iter % 5 };
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+
+        let (hits, misses) = store.cache_stats();
+        assert_eq!(hits + misses, 1000);
+        assert!(misses < 100, "{} reads went to disk", misses);
+        Ok(())
+    }
+
+    // Cached values should be invalidated by overwrites and removals.
+    #[test]
+    fn value_cache_invalidation() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            value_cache_capacity: 1_000,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.cache_stats(), (1, 1));
+
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    }
+
+    // Entries in newer fragments should replace the ones in older fragments.
+    #[test]
+    fn read_across_fragments() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        // Produce a newer fragment in a separate store and move it over.
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            initial_fragment: 1,
+            ..Default::default()
+        };
+        let mut other = KvStore::open_with_options(other_dir.path(), options)?;
+        other.set("key1".to_owned(), "value3".to_owned())?;
+        drop(other);
+        std::fs::rename(
+            other_dir.path().join(fragment_filename(1)),
+            temp_dir.path().join(fragment_filename(1)),
+        )?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.active_fragment(), 1);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Compaction should report every fragment it replaced.
+    #[test]
+    fn compact_removed_fragments() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            initial_fragment: 1,
+            ..Default::default()
+        };
+        let mut other = KvStore::open_with_options(other_dir.path(), options)?;
+        other.set("key1".to_owned(), "value3".to_owned())?;
+        drop(other);
+        std::fs::rename(
+            other_dir.path().join(fragment_filename(1)),
+            temp_dir.path().join(fragment_filename(1)),
+        )?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut before: Vec<_> = store.shared.lock_log().fragments.iter().copied().collect();
+        let removed = store.compact()?;
+        before.retain(|&fragment| fragment != store.active_fragment());
+        assert_eq!(removed, before);
+        assert_eq!(removed, vec![0, 1]);
+        assert_eq!(store.active_fragment(), 2);
+        for fragment in removed {
+            assert!(!temp_dir.path().join(fragment_filename(fragment)).exists());
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Compaction should split live entries across fragments bounded by
+    // max_fragment_bytes.
+    #[test]
+    fn bounded_compacted_fragments() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .max_fragment_bytes(200)
+            .open(temp_dir.path())?;
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set("key0".to_owned(), "value0".to_owned())?;
+
+        let removed = store.compact()?;
+        assert_eq!(removed, vec![0]);
+        assert!(store.fragment_count() > 1);
+        let mut fragments = Vec::new();
+        for entry in temp_dir.path().read_dir()? {
+            let path = entry?.path();
+            assert!(std::fs::metadata(&path)?.len() <= 200);
+            fragments.push(fragment_generation(&path)?);
+        }
+        fragments.sort_unstable();
+        let expected: Vec<u64> = (1..=store.active_fragment()).collect();
+        assert_eq!(fragments, expected);
+        assert!(store
+            .location_map()
+            .values()
+            .all(|ep| fragments.contains(&ep.fragment)));
+
+        for key_id in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.len(), 50);
+        for key_id in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    }
+
+    // Fragments vanishing while loading should be skipped.
+    #[test]
+    fn vanished_fragment() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        let paths = vec![
+            (0, temp_dir.path().join(fragment_filename(0))),
+            (1, temp_dir.path().join(fragment_filename(1))),
+        ];
+        let mut index = Index::default();
+        let (readers, _) = load_fragments(paths, Codec::default(), MAX_ENTRY_SIZE, &mut index)?;
+        assert_eq!(readers.keys().collect::<Vec<_>>(), vec![&0]);
+        assert!(index.get(DEFAULT_NAMESPACE, "key1").is_some());
+        Ok(())
+    }
+
+    // Two fragments parsing to the same generation should fail opening,
+    // naming both, instead of one of them being ignored.
+    #[test]
+    fn duplicate_generation() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        std::fs::copy(
+            temp_dir.path().join(fragment_filename(0)),
+            temp_dir.path().join("00.kv"),
+        )?;
+
+        for _ in 0..2 {
+            match KvStore::open(temp_dir.path()) {
+                Err(StoreError::Fragment(msg)) => {
+                    assert!(msg.contains("generation 0"), "{}", msg);
+                    let expected = format!(
+                        "{} and {}",
+                        temp_dir.path().join("0.kv").display(),
+                        temp_dir.path().join("00.kv").display()
+                    );
+                    assert!(msg.contains(&expected), "{}", msg);
+                }
+                res => panic!("expected duplicate generation error, got {:?}", res.err()),
+            }
+        }
+        Ok(())
+    }
+
+    // A partially written entry at the end of the log should be truncated
+    // away when opening, keeping every entry before it.
+    #[test]
+    fn partial_trailing_entry() -> Result<()> {
+        for codec in [Codec::Json, Codec::Binary] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let builder = KvStore::builder().codec(codec);
+            let mut store = builder.clone().open(temp_dir.path())?;
+            store.set("key1".to_owned(), "value1".to_owned())?;
+            store.set("key2".to_owned(), "value2".to_owned())?;
+            drop(store);
+
+            let path = temp_dir.path().join(fragment_filename(0));
+            let len = std::fs::metadata(&path)?.len();
+            let entry = codec.encode(&LogEntry::Set {
+                ns: String::new(),
+                key: "key3".to_owned(),
+                value: "value3".to_owned(),
+            })?;
+            let mut file = OpenOptions::new().append(true).open(&path)?;
+            file.write_all(&entry[..entry.len() / 2])?;
+            drop(file);
+
+            let mut store = builder.clone().open(temp_dir.path())?;
+            assert_eq!(std::fs::metadata(&path)?.len(), len, "{:?}", codec);
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            assert_eq!(store.get("key3".to_owned())?, None);
+            store.set("key3".to_owned(), "value3".to_owned())?;
+            drop(store);
+
+            let store = builder.open(temp_dir.path())?;
+            assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        }
+        Ok(())
+    }
+
+    // A partially written entry followed by more of the log is corruption,
+    // not a crash while writing.
+    #[test]
+    fn partial_entry_before_newer_fragment() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        let path = temp_dir.path().join(fragment_filename(0));
+        let len = std::fs::metadata(&path)?.len();
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        // The tag of a set entry and half of its key length
+        file.write_all(&[0, 4, 0])?;
+        drop(file);
+        std::fs::write(temp_dir.path().join(fragment_filename(1)), BINARY_HEADER)?;
+
+        match KvStore::open(temp_dir.path()) {
+            Err(StoreError::Fragment(msg)) => {
+                assert!(msg.contains("fragment 0"), "{}", msg);
+                assert!(msg.contains(&len.to_string()), "{}", msg);
+            }
+            res => panic!("expected partial entry error, got {:?}", res.err()),
+        }
+        assert_eq!(std::fs::metadata(&path)?.len(), len + 3);
+        Ok(())
+    }
+
+    // A corrupt length running past the end of the active fragment should
+    // not be mistaken for a partially written entry, discarding the entries
+    // after it.
+    #[test]
+    fn corrupt_length_in_active_fragment() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let path = temp_dir.path().join(fragment_filename(0));
+        let mut fragment = std::fs::read(&path)?;
+        // The value length of the first entry, after its tag and key
+        let offset = BINARY_HEADER.len() + 1 + 4 + "key1".len();
+        fragment[offset..offset + 4].copy_from_slice(&1000u32.to_le_bytes());
+        std::fs::write(&path, &fragment)?;
+
+        match KvStore::open(temp_dir.path()) {
+            Err(StoreError::Fragment(msg)) => {
+                assert!(msg.contains("fragment 0"), "{}", msg);
+                assert!(msg.contains(&BINARY_HEADER.len().to_string()), "{}", msg);
+            }
+            res => panic!("expected corrupt entry error, got {:?}", res.err()),
+        }
+        assert_eq!(std::fs::metadata(&path)?.len(), fragment.len() as u64);
+        Ok(())
+    }
+
+    // A missing active fragment should be replaced by a fresh fragment.
+    #[test]
+    fn missing_active_fragment() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut readers = HashMap::new();
+
+        let (fragment, _) =
+            open_active_fragment(temp_dir.path(), 3, Codec::default(), &mut readers)?;
+        assert_eq!(fragment, 4);
+        assert!(readers.contains_key(&4));
+        assert!(temp_dir.path().join(fragment_filename(4)).exists());
+        Ok(())
+    }
+
+    // Scan should return the latest value of every key with the prefix.
+    #[test]
+    fn scan_prefix() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("user:2".to_owned(), "bob".to_owned())?;
+        store.set("user:1".to_owned(), "alice".to_owned())?;
+        store.set("user:3".to_owned(), "carol".to_owned())?;
+        store.set("group:1".to_owned(), "admins".to_owned())?;
+        store.set("user:1".to_owned(), "alice2".to_owned())?;
+        store.remove("user:3".to_owned())?;
+
+        let expected = vec![
+            ("user:1".to_owned(), "alice2".to_owned()),
+            ("user:2".to_owned(), "bob".to_owned()),
+        ];
+        assert_eq!(store.scan("user:")?, expected);
+        assert_eq!(store.scan("none:")?, vec![]);
+        assert_eq!(store.scan("")?.len(), 3);
+        Ok(())
+    }
+
+    // Range scans should return the latest value of every live key in bounds.
+    #[test]
+    fn scan_range() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut model = BTreeMap::new();
+        for key_id in 0..=100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            model.insert(format!("key{}", key_id), format!("value{}", key_id));
+        }
+        store.set("key2".to_owned(), "value2b".to_owned())?;
+        model.insert("key2".to_owned(), "value2b".to_owned());
+        store.remove("key25".to_owned())?;
+        model.remove("key25");
+
+        let expected: Vec<(String, String)> = model
+            .range::<str, _>((Bound::Included("key1"), Bound::Excluded("key3")))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        // key1, key10..key19, key100, key2 and key20..key29 without key25
+        assert_eq!(expected.len(), 22);
+        assert_eq!(
+            store.range(Bound::Included("key1"), Bound::Excluded("key3"))?,
+            expected
+        );
+
+        assert_eq!(
+            store.range(Bound::Excluded("key98"), Bound::Unbounded)?,
+            vec![("key99".to_owned(), "value99".to_owned())]
+        );
+        assert!(store
+            .range(Bound::Included("key3"), Bound::Excluded("key1"))?
+            .is_empty());
+        assert!(store
+            .range(Bound::Excluded("key1"), Bound::Excluded("key1"))?
+            .is_empty());
+        Ok(())
+    }
+
+    // A batch should leave the store in the same state as individual sets.
+    #[test]
+    fn set_batch() -> Result<()> {
+        let batch_dir = TempDir::new().expect("unable to create temporary working directory");
+        let single_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut batch_store = KvStore::open(batch_dir.path())?;
+        let mut single_store = KvStore::open(single_dir.path())?;
+
+        let entries: Vec<(String, String)> = (0..10_000)
+            .map(|id| (format!("key{}", id % 9_000), format!("value{}", id)))
+            .collect();
+        batch_store.set_batch(entries.clone())?;
+        for (key, value) in entries {
+            single_store.set(key, value)?;
+        }
+
+        let expected = single_store.to_map()?;
+        assert_eq!(expected.len(), 9_000);
+        assert_eq!(expected["key0"], "value9000");
+        assert_eq!(batch_store.to_map()?, expected);
+
+        drop(batch_store);
+        let mut batch_store = KvStore::open(batch_dir.path())?;
+        assert_eq!(batch_store.to_map()?, expected);
+        Ok(())
+    }
+
+    // Keys set with a TTL should read as absent once expired, also after
+    // reopening the store.
+    #[test]
+    fn set_with_ttl() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(50),
+        )?;
+        store.set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(60),
+        )?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key2", "key3"]);
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(StoreError::NotFound)
+        ));
+
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.len(), 2);
+
+        // Setting a key without a TTL clears its expiry.
+        store.set_with_ttl(
+            "key3".to_owned(),
+            "value3".to_owned(),
+            Duration::from_millis(50),
+        )?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        Ok(())
+    }
+
+    // Compaction should drop expired entries from the log.
+    #[test]
+    fn expired_space_reclaimed() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let value = "v".repeat(10_000);
+        store.set_with_ttl("key2".to_owned(), value, Duration::from_millis(10))?;
+        std::thread::sleep(Duration::from_millis(50));
+
+        store.compact()?;
+        let fragment = temp_dir
+            .path()
+            .join(fragment_filename(store.active_fragment()));
+        let live = store.entry_disk_size("key1").unwrap() + Codec::Binary.header().len();
+        assert_eq!(std::fs::metadata(fragment)?.len(), live as u64);
+        assert_eq!(store.unreclaimed_space(), 0);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        Ok(())
+    }
+
+    // A batch with an entry that can not be written should write none of its
+    // entries.
+    #[test]
+    fn set_batch_too_large() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = KvStore::builder().max_entry_size(100);
+        let mut store = builder.clone().open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let res = store.set_batch(vec![
+            ("key1".to_owned(), "value2".to_owned()),
+            ("key2".to_owned(), "v".repeat(200)),
+        ]);
+        assert!(matches!(res, Err(StoreError::EntryTooLarge { .. })));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        drop(store);
+
+        let store = builder.open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        Ok(())
+    }
+
+    // Identical writes within a batch should be skipped, including repeats of
+    // entries written earlier in the same batch.
+    #[test]
+    fn set_batch_skip_identical_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .skip_identical_writes(true)
+            .open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        store.set_batch(vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ])?;
+        assert_eq!(store.unreclaimed_space(), 0);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Always synced writes should be on disk even if the store is leaked.
+    #[test]
+    fn sync_policy_always() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .sync_policy(SyncPolicy::Always)
+            .open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        std::mem::forget(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Unsynced writes should stay buffered but remain readable.
+    #[test]
+    fn sync_policy_never() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .sync_policy(SyncPolicy::Never)
+            .compaction_threshold(100)
+            .open(temp_dir.path())?;
+        let fragment_len = |fragment| {
+            std::fs::metadata(temp_dir.path().join(fragment_filename(fragment)))
+                .expect("missing fragment")
+                .len()
+        };
+        let empty_len = fragment_len(0);
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(fragment_len(0), empty_len);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        // Compaction should copy buffered entries.
+        while store.active_fragment() == 0 {
+            store.set("key3".to_owned(), "value3".to_owned())?;
+        }
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        let compacted_len = fragment_len(1);
+        store.sync()?;
+        assert!(fragment_len(1) > compacted_len);
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        for id in 1..=4 {
+            assert_eq!(
+                store.get(format!("key{}", id))?,
+                Some(format!("value{}", id))
+            );
+        }
+        Ok(())
+    }
+
+    // Contains should reflect sets, removes and expiry without reading values.
+    #[test]
+    fn contains_key() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::ZERO)?;
+
+        assert!(store.contains_key("key1"));
+        assert!(!store.contains_key("key2"));
+        assert!(!store.contains_key("key3"));
+        assert!(!store.contains_key("key4"));
+        assert_eq!(store.cache_stats(), (0, 0));
+        Ok(())
+    }
+
+    // keys and len should only cover live keys.
+    #[test]
+    fn keys_and_len() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(store.is_empty());
+
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+
+        assert_eq!(store.len(), 2);
+        assert!(!store.is_empty());
+        assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key2", "key3"]);
+        Ok(())
+    }
+
+    // to_map should contain exactly the live entries of the store.
+    #[test]
+    fn to_map_live_entries() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.set("key1".to_owned(), "value4".to_owned())?;
+        store.remove("key2".to_owned())?;
+
+        let expected = BTreeMap::from([
+            ("key1".to_owned(), "value4".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]);
+        assert_eq!(store.to_map()?, expected);
+
+        // The map should be the same after reopening the store.
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.to_map()?, expected);
+        Ok(())
+    }
+
+    fn merge_stores(conflict: ConflictPolicy) -> Result<(usize, KvStore, TempDir)> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut other = KvStore::open(other_dir.path())?;
+
+        store.set("key1".to_owned(), "mine1".to_owned())?;
+        store.set("key2".to_owned(), "mine2".to_owned())?;
+        other.set("key2".to_owned(), "theirs2".to_owned())?;
+        other.set("key3".to_owned(), "theirs3".to_owned())?;
+        other.set("key4".to_owned(), "theirs4".to_owned())?;
+        other.remove("key4".to_owned())?;
+
+        let merged = store.merge_from(&mut other, conflict)?;
+        Ok((merged, store, temp_dir))
+    }
+
+    #[test]
+    fn merge_keep_mine() -> Result<()> {
+        let (merged, store, _temp_dir) = merge_stores(ConflictPolicy::KeepMine)?;
+        assert_eq!(merged, 1);
+        assert_eq!(store.get("key1".to_owned())?, Some("mine1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("mine2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("theirs3".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_take_theirs() -> Result<()> {
+        let (merged, store, _temp_dir) = merge_stores(ConflictPolicy::TakeTheirs)?;
+        assert_eq!(merged, 2);
+        assert_eq!(store.get("key1".to_owned())?, Some("mine1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("theirs2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("theirs3".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        Ok(())
+    }
+
+    // Merging should keep the expiry of expiring keys.
+    #[test]
+    fn merge_expiring() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut other = KvStore::open(other_dir.path())?;
+        other.set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(100),
+        )?;
+        other.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert_eq!(store.merge_from(&mut other, ConflictPolicy::TakeTheirs)?, 2);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    }
+
+    // Merging should copy the keys of every namespace into the same
+    // namespace.
+    #[test]
+    fn merge_namespaces() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut other = KvStore::open(other_dir.path())?;
+        store
+            .namespace("ns1")
+            .set("key1".to_owned(), "mine1".to_owned())?;
+        other
+            .namespace("ns1")
+            .set("key1".to_owned(), "theirs1".to_owned())?;
+        other
+            .namespace("ns2")
+            .set("key1".to_owned(), "theirs2".to_owned())?;
+        other.set("key1".to_owned(), "theirs3".to_owned())?;
+
+        assert_eq!(store.merge_from(&mut other, ConflictPolicy::KeepMine)?, 2);
+        assert_eq!(
+            store.namespace("ns1").get("key1".to_owned())?,
+            Some("mine1".to_owned())
+        );
+        assert_eq!(
+            store.namespace("ns2").get("key1".to_owned())?,
+            Some("theirs2".to_owned())
+        );
+        assert_eq!(store.get("key1".to_owned())?, Some("theirs3".to_owned()));
+        Ok(())
+    }
+
+    // Merging should copy byte values that are not valid UTF-8 as they are.
+    #[test]
+    fn merge_bytes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut other = KvStore::open(other_dir.path())?;
+        other.set_bytes("key1".to_owned(), vec![0xff, 0])?;
+        other.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert_eq!(store.merge_from(&mut other, ConflictPolicy::TakeTheirs)?, 2);
+        assert_eq!(store.get_bytes("key1".to_owned())?, Some(vec![0xff, 0]));
+        assert!(matches!(
+            store.get("key1".to_owned()),
+            Err(StoreError::Utf8(_))
+        ));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Location map should point at each key's latest entry.
+    #[test]
+    fn location_map() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        let locations = store.location_map();
+        assert_eq!(locations.len(), 2);
+        let key1 = &locations["key1"];
+        let key2 = &locations["key2"];
+        let header = BINARY_HEADER.len() as u64;
+        assert_eq!((key1.fragment, key1.pos), (0, header));
+        assert_eq!((key2.fragment, key2.pos), (0, header + key1.size as u64));
+
+        // Trigger a compaction; every entry moves to the new fragment.
+        let value = "v".repeat(10_000);
+        for _ in 0..150 {
+            store.set("key3".to_owned(), value.clone())?;
+        }
+        let locations = store.location_map();
+        assert_eq!(locations.len(), 3);
+        assert!(locations.values().all(|ep| ep.fragment == 1));
+        assert_eq!(locations["key1"].size, key1.size);
+        assert_eq!(locations["key2"].size, key2.size);
+        assert_ne!(locations["key1"].pos, locations["key2"].pos);
+        Ok(())
+    }
+
+    // Replaced fragments should be trashed and purged by the next compaction.
+    #[test]
+    fn trash_retention() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            trash_retention: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let trash = temp_dir.path().join(TRASH_DIR);
+
+        let value = "v".repeat(10_000);
+        let compact = |store: &mut KvStore| -> Result<()> {
+            let fragment = store.active_fragment();
+            while store.active_fragment() == fragment {
+                store.set("key1".to_owned(), value.clone())?;
+            }
+            Ok(())
+        };
+
+        compact(&mut store)?;
+        assert!(trash.join(fragment_filename(0)).exists());
+        assert!(!temp_dir.path().join(fragment_filename(0)).exists());
+
+        compact(&mut store)?;
+        assert!(!trash.join(fragment_filename(0)).exists());
+        assert!(trash.join(fragment_filename(1)).exists());
+
+        // Trashed fragments are not part of the store.
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.fragment_count(), 1);
+        assert_eq!(store.get("key1".to_owned())?, Some(value));
+        Ok(())
+    }
+
+    // Failing to purge the trash should not fail the compaction, which has
+    // already replaced the old fragments by then.
+    #[test]
+    fn unpurgeable_trash() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .trash_retention(Duration::ZERO)
+            .compaction_threshold(usize::MAX)
+            .open(temp_dir.path())?;
+        // Directories can not be removed as files.
+        std::fs::create_dir_all(temp_dir.path().join(TRASH_DIR).join("stuck"))?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.compact()?, vec![0]);
+        assert_eq!(store.fragment_count(), 1);
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        store.compact()?;
+        assert_eq!(store.fragment_count(), 1);
+        assert!(!temp_dir.path().join(fragment_filename(1)).exists());
+        Ok(())
+    }
+
+    // A replaced fragment that can not be removed should stay part of the
+    // store, so the next compaction removes it, without failing the
+    // compaction that replaced it or being loaded when reopening.
+    #[test]
+    fn unremovable_fragment() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = KvStore::builder()
+            .compaction_threshold(usize::MAX)
+            .max_fragment_bytes(1);
+        let mut store = builder.clone().open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.compact()?, vec![0]);
+        // The set of key1 stays in fragment 1, its tombstone goes to the
+        // active fragment 2.
+        assert_eq!(store.active_fragment(), 2);
+        store.remove("key1".to_owned())?;
+
+        // The store keeps reading and writing through its open handles, while
+        // removing the path fails.
+        let path = temp_dir.path().join(fragment_filename(1));
+        let moved = temp_dir.path().join("moved");
+        std::fs::rename(&path, &moved)?;
+        std::fs::create_dir(&path)?;
+        assert_eq!(store.compact()?, vec![2]);
+        assert_eq!(store.fragment_count(), 2);
+        assert_eq!(store.active_fragment(), 3);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        store.reindex()?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        // Reopening should not load the replaced fragment either.
+        std::fs::remove_dir(&path)?;
+        std::fs::rename(&moved, &path)?;
+        drop(store);
+        let mut store = builder.open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.fragment_count(), 2);
+
+        assert_eq!(store.compact()?, vec![1, 3]);
+        assert_eq!(store.fragment_count(), 1);
+        assert!(!path.exists());
+        assert!(!temp_dir.path().join(REPLACED_FILE).exists());
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // Interleaved reads at different offsets of one fragment, mixed with
+    // writes to it, should never observe stale reader buffers.
+    #[test]
+    fn interleaved_reads() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut expected = HashMap::new();
+
+        for key_id in 0..200 {
+            let value = format!("{}{}", "v".repeat(key_id % 13), key_id);
+            store.set(format!("key{}", key_id), value.clone())?;
+            expected.insert(format!("key{}", key_id), value);
+        }
+
+        for round in 0..5 {
+            // Jump back and forth across the fragment.
+            for step in 0..200 {
+                for key_id in [(step * 37) % 200, 199 - (step * 37) % 200, step] {
+                    let key = format!("key{}", key_id);
+                    assert_eq!(store.get(key.clone())?.as_ref(), expected.get(&key));
+                }
+            }
+
+            // Append to the fragment between reads of older entries.
+            for key_id in (round..200).step_by(round + 2) {
+                let key = format!("key{}", key_id);
+                let value = format!("{}-{}", key_id, round);
+                store.set(key.clone(), value.clone())?;
+                expected.insert(key.clone(), value);
+
+                let other = format!("key{}", (key_id + 100) % 200);
+                assert_eq!(store.get(other.clone())?.as_ref(), expected.get(&other));
+                assert_eq!(store.get(key.clone())?.as_ref(), expected.get(&key));
+            }
+        }
+        Ok(())
+    }
+
+    // Clones reading on other threads should only ever observe values the
+    // writer already wrote, in order, across compactions.
+    #[test]
+    fn concurrent_clones() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .compaction_threshold(10_000)
+            .value_cache_capacity(1024)
+            .open(temp_dir.path())?;
+        store.set("done".to_owned(), "false".to_owned())?;
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    let mut last = [0; 10];
+                    while store.get("done".to_owned())?.as_deref() == Some("false") {
+                        for (key_id, last) in last.iter_mut().enumerate() {
+                            let value = store.get(format!("key{}", key_id))?;
+                            let round = value.map_or(0, |v| v.parse().unwrap());
+                            assert!(round >= *last, "value of key{} went back", key_id);
+                            *last = round;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        for round in 1..=300 {
+            for key_id in 0..10 {
+                store.set(format!("key{}", key_id), round.to_string())?;
+            }
+        }
+        store.set("done".to_owned(), "true".to_owned())?;
+        for reader in readers {
+            reader.join().expect("reader thread panicked")?;
+        }
+
+        assert!(store.active_fragment() > 0);
+        let reader = store.clone();
+        assert_eq!(reader.get("key9".to_owned())?, Some("300".to_owned()));
+        Ok(())
+    }
+
+    // Newline delimited fragments should hold one JSON entry per line.
+    #[test]
+    fn newline_delimited() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            newline_delimited: true,
+            codec: Some(Codec::Json),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        let check_lines = |fragment: u64, count: usize| {
+            let contents =
+                std::fs::read_to_string(temp_dir.path().join(fragment_filename(fragment)))
+                    .expect("unable to read fragment");
+            assert!(contents.ends_with('\n'));
+            let lines: Vec<&str> = contents.lines().collect();
+            assert_eq!(lines.len(), count);
+            for line in lines {
+                serde_json::from_str::<serde_json::Value>(line).expect("invalid JSON line");
+            }
+        };
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.remove("key2".to_owned())?;
+        check_lines(0, 4);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        // Compaction keeps the delimiters.
+        let value = "v".repeat(10_000);
+        while store.active_fragment() == 0 {
+            store.set("key3".to_owned(), value.clone())?;
+        }
+        check_lines(1, 2);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some(value));
+        Ok(())
+    }
+
+    // Padding between JSON entries would skew positions and should be
+    // detected on open.
+    #[test]
+    fn json_padding_detected() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .codec(Codec::Json)
+            .open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let offset = store.entry_disk_size("key1").unwrap();
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let path = temp_dir.path().join(fragment_filename(0));
+        let mut contents = std::fs::read(&path)?;
+        contents.insert(offset, b' ');
+        std::fs::write(&path, contents)?;
+
+        assert!(matches!(
+            KvStore::open(temp_dir.path()),
+            Err(StoreError::Fragment(_))
+        ));
+        Ok(())
+    }
+
+    // Binary fragments should start with the format header and survive a
+    // reopen.
+    #[test]
+    fn binary_codec() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+
+        let contents = std::fs::read(temp_dir.path().join(fragment_filename(0)))?;
+        assert!(contents.starts_with(&BINARY_HEADER));
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        Ok(())
+    }
+
+    // The codec of an existing store should be detected when not set.
+    #[test]
+    fn detect_codec() -> Result<()> {
+        for codec in [Codec::Json, Codec::Binary] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = KvStoreOptions {
+                codec: Some(codec),
+                ..Default::default()
+            };
+            let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+            store.set("key1".to_owned(), "value1".to_owned())?;
+            drop(store);
+
+            let mut store = KvStore::open(temp_dir.path())?;
+            assert_eq!(store.shared.codec, codec);
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            store.set("key2".to_owned(), "value2".to_owned())?;
+            drop(store);
+
+            let store = KvStore::open(temp_dir.path())?;
+            assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        }
+        Ok(())
+    }
+
+    // Forcing a codec the store was not written with should fail clearly.
+    #[test]
+    fn forced_codec_mismatch() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            codec: Some(Codec::Json),
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        let options = KvStoreOptions {
+            codec: Some(Codec::Binary),
+            ..Default::default()
+        };
+        assert!(matches!(
+            KvStore::open_with_options(temp_dir.path(), options),
+            Err(StoreError::CodecMismatch {
+                requested: Codec::Binary,
+                found: Codec::Json,
+            })
+        ));
+        Ok(())
+    }
+
+    // Identical overwrites should not grow the log.
+    #[test]
+    fn skip_identical_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            skip_identical_writes: true,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+        let fragment_len = || {
+            std::fs::metadata(temp_dir.path().join(fragment_filename(0)))
+                .expect("missing fragment")
+                .len()
+        };
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let len = fragment_len();
+        for _ in 0..10 {
+            store.set("key1".to_owned(), "value1".to_owned())?;
+        }
+        assert_eq!(fragment_len(), len);
+        assert_eq!(store.unreclaimed_space(), 0);
+
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert!(fragment_len() > len);
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        // Hashes are rebuilt when the store is reopened.
+        drop(store);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        let len = fragment_len();
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(fragment_len(), len);
+        Ok(())
+    }
+
+    // Compacted positions should tile the new fragment exactly.
+    #[test]
+    fn compacted_positions() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        let value = "v".repeat(10_000);
+        while store.active_fragment() == 0 {
+            store.set("big".to_owned(), value.clone())?;
+        }
+
+        let mut positions: Vec<_> = store
+            .shared
+            .read_index()
+            .namespace(DEFAULT_NAMESPACE)
+            .values()
+            .map(|ep| (ep.pos, ep.size))
+            .collect();
+        positions.sort_unstable();
+        let mut end = BINARY_HEADER.len() as u64;
+        for (pos, size) in positions {
+            assert_eq!(pos, end);
+            end += size as u64;
+        }
+        let len = std::fs::metadata(temp_dir.path().join(fragment_filename(1)))?.len();
+        assert_eq!(end, len);
+
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    }
+
+    // Ordered compaction should lay out keys sharing a prefix contiguously.
+    #[test]
+    fn ordered_compaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            ordered_compaction: true,
+            ..Default::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
 
-/// File extension for logs
-pub const LOG_EXTENSION: &str = "kv";
+        for key_id in (0..100).rev() {
+            store.set(format!("user:{:03}", key_id), format!("{}", key_id))?;
+            store.set(format!("group:{:03}", key_id), format!("{}", key_id))?;
+        }
+        let value = "v".repeat(10_000);
+        while store.active_fragment() == 0 {
+            store.set("zzz".to_owned(), value.clone())?;
+        }
 
-/// Byte threshold of unclaimed space that should trigger compaction
-///
-/// Default: 1MB
-const COMPACTION_THRESHOLD: usize = 1_000_000;
+        let pairs = store.scan("user:")?;
+        assert_eq!(pairs.len(), 100);
+        let locations = store.location_map();
+        let mut end = locations[&pairs[0].0].pos;
+        for (key, value) in pairs {
+            let ep = &locations[&key];
+            assert_eq!(ep.pos, end, "{} is not contiguous", key);
+            assert_eq!(store.get(key)?, Some(value));
+            end = ep.pos + ep.size as u64;
+        }
+        Ok(())
+    }
 
-/// A list specifying supported Write-Ahead Log(WAL) entries.
-#[derive(Debug, Deserialize, Serialize)]
-pub(crate) enum LogEntry {
-    Set { key: String, value: String },
-    Rm { key: String },
-}
+    // Every operation should emit an event carrying its name, key, value size
+    // and duration.
+    #[test]
+    fn operation_events() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .finish();
 
-/// Represents the location of an entry in the log fragments.
-#[derive(Debug, Clone)]
-pub struct EntryPosition {
-    /// Fragment the entry is currently located in.
-    pub fragment: u64,
-    /// Position of the entry in the fragment
-    pub pos: u64,
-    /// Size of the entry
-    pub size: usize,
-}
+        tracing::subscriber::with_default(subscriber, || {
+            let mut store = KvStore::open(temp_dir.path())?;
+            store.set("key1".to_owned(), "value1".to_owned())?;
+            store.get("key1".to_owned())?;
+            assert!(store.remove("key2".to_owned()).is_err());
+            Ok::<_, StoreError>(())
+        })?;
 
-impl From<(u64, Range<u64>)> for EntryPosition {
-    fn from(value: (u64, Range<u64>)) -> Self {
-        Self {
-            fragment: value.0,
-            pos: value.1.start,
-            size: (value.1.end - value.1.start) as usize,
+        let contents = logs.contents();
+        for (op, event) in [
+            (
+                "op=\"set\" key=\"key1\"",
+                "operation finished bytes=6 duration_ms=",
+            ),
+            (
+                "op=\"get\" key=\"key1\"",
+                "operation finished bytes=6 duration_ms=",
+            ),
+            (
+                "op=\"remove\" key=\"key2\"",
+                "operation failed error=Key not found duration_ms=",
+            ),
+        ] {
+            let expected = format!("op{{{}}}: kvs::engine: {}", op, event);
+            assert!(
+                contents.contains(&expected),
+                "missing {:?} in {}",
+                expected,
+                contents
+            );
         }
+        Ok(())
     }
-}
 
-/// Represents a key-value store.
-pub struct KvStore {
-    dir: PathBuf,
-    unreclaimed_space: usize,
-    fragment: u64,
-    fragment_readers: HashMap<u64, BufReader<File>>,
-    index: HashMap<String, EntryPosition>,
-    writer: BufWriter<File>,
-}
+    // Compaction should emit start and end events.
+    #[test]
+    fn compaction_events() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
 
-impl KvStore {
-    /// Opens a key-value store at the given directory path.
-    ///
-    ///
-    /// If Key-Value store exists at the path, the pre-existing stores index is
-    /// loaded into memory and subsequent changes are stored.
-    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
-        let dir: PathBuf = dir.into();
-        let mut fragment = 0;
-        let mut index = HashMap::new();
-        let mut unreclaimed_space = 0;
+        tracing::subscriber::with_default(subscriber, || {
+            let mut store = KvStore::open(temp_dir.path())?;
+            let value = "v".repeat(10_000);
+            while store.active_fragment() == 0 {
+                store.set("key1".to_owned(), value.clone())?;
+            }
+            Ok::<_, StoreError>(())
+        })?;
 
-        // Load all pre-existing fragments
-        // NOTE: I'm both proud and scared of what I've done here...
-        let mut fragment_readers = dir
-            .read_dir()?
-            .filter(|res| res.is_ok())
-            .map(|res| res.unwrap().path())
-            .filter(|path| {
-                path.extension()
-                    .and_then(|ext| ext.to_str())
-                    .map(|ext| ext == LOG_EXTENSION)
-                    .unwrap_or(false)
-            })
-            .map(|path| {
-                load_fragment(path, &mut index).map(|(frag, c_space, reader)| {
-                    if frag > fragment {
-                        fragment = frag;
-                    }
-                    unreclaimed_space += c_space;
-                    (frag, reader)
-                })
-            })
-            .collect::<Result<HashMap<u64, BufReader<File>>>>()?;
+        let contents = logs.contents();
+        let started = contents
+            .find("kvs::engine: compaction started fragment=1")
+            .expect("missing compaction start event");
+        let finished = contents
+            .find("kvs::engine: compaction finished fragment=1")
+            .expect("missing compaction end event");
+        assert!(started < finished);
+        Ok(())
+    }
 
-        // Open latest fragment for read or create a new fragment
-        // if non exist
-        let file = if fragment_readers.is_empty() {
-            let file = new_fragment(fragment, &dir)?;
-            fragment_readers.insert(fragment, BufReader::new(file.try_clone()?));
-            file
-        } else {
-            let path = dir.join(format!("{}.{}", fragment, LOG_EXTENSION));
-            OpenOptions::new().write(true).open(path)?
+    // Introspection accessors should track writes and compactions.
+    #[test]
+    fn introspection() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            initial_fragment: 5,
+            ..Default::default()
         };
-        let writer = BufWriter::new(file);
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.compaction_threshold(), COMPACTION_THRESHOLD);
+        assert_eq!(store.active_fragment(), 5);
+        assert_eq!(store.fragment_count(), 1);
+        assert_eq!(store.unreclaimed_space(), 0);
 
-        let mut store = Self {
-            dir,
-            unreclaimed_space,
-            fragment,
-            fragment_readers,
-            index,
-            writer,
-        };
-        store.compact()?;
-        Ok(store)
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let size = store.entry_disk_size("key1").unwrap();
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.unreclaimed_space(), size);
+
+        let value = "v".repeat(10_000);
+        for _ in 0..150 {
+            store.set("key1".to_owned(), value.clone())?;
+        }
+        assert_eq!(store.active_fragment(), 6);
+        assert_eq!(store.fragment_count(), 1);
+        assert!(store.unreclaimed_space() < store.compaction_threshold());
+        Ok(())
     }
 
-    /// Compacts the Key-Value databases log.
-    ///
-    /// Compaction clears outdated entries from the stores log fragments, generating
-    /// a new log fragment with up to date values.
-    fn compact(&mut self) -> Result<()> {
-        if self.unreclaimed_space > COMPACTION_THRESHOLD {
-            let new_gen = self.fragment + 1;
-            // Store new fragment in temp till the compaction is succesful.
-            // Avoid corrupting the stores directory due to failed compaction.
-            let fragment = new_fragment(new_gen, &std::env::temp_dir())?;
-            let mut writer = BufWriter::new(fragment.try_clone()?);
-
-            let mut index = self.index.clone();
-            for (key, ep) in index.iter_mut() {
-                let reader =
-                    self.fragment_readers
-                        .get_mut(&ep.fragment)
-                        .ok_or(StoreError::Fragment(format!(
-                            "[Gen({})] missing fragment reader {} for entry {}",
-                            new_gen, ep.fragment, key
-                        )))?;
-                reader.seek(SeekFrom::Start(ep.pos))?;
-
-                let mut buf = vec![0; ep.size];
-                reader.read_exact(&mut buf)?;
-
-                ep.pos = writer.seek(SeekFrom::End(0))?;
-                ep.fragment = new_gen;
-                writer.write_all(&buf)?;
-            }
+    // Options set through the builder should take effect.
+    #[test]
+    fn builder_options() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .initial_fragment(3)
+            .codec(Codec::Json)
+            .newline_delimited(true)
+            .compaction_threshold(100)
+            .value_cache_capacity(1024)
+            .open(temp_dir.path())?;
+        assert_eq!(store.active_fragment(), 3);
+        assert_eq!(store.shared.codec, Codec::Json);
+        assert!(store.shared.newline_delimited);
+        assert_eq!(store.compaction_threshold(), 100);
 
-            writer.flush()?;
-            std::fs::rename(
-                std::env::temp_dir().join(fragment_filename(new_gen)),
-                self.dir.join(fragment_filename(new_gen)),
-            )?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.get("key1".to_owned())?;
+        store.get("key1".to_owned())?;
+        assert_eq!(store.cache_stats(), (1, 1));
 
-            // Compaction is done; old versions are safe to delete now.
-            let reader = BufReader::new(fragment);
-            self.writer = writer;
-            self.fragment = new_gen;
-            self.index = index;
-            self.unreclaimed_space = 0;
-            for (old_fragment, reader) in self.fragment_readers.drain() {
-                drop(reader);
-                std::fs::remove_file(self.dir.join(fragment_filename(old_fragment)))?;
-            }
-            self.fragment_readers.insert(new_gen, reader);
+        while store.active_fragment() == 3 {
+            store.set("key1".to_owned(), "value1".to_owned())?;
         }
+        let contents = std::fs::read_to_string(temp_dir.path().join(fragment_filename(4)))?;
+        assert_eq!(
+            contents,
+            "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n"
+        );
         Ok(())
     }
-}
 
-impl KvEngine for KvStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let entry = LogEntry::Set {
-            key: key.clone(),
-            value,
+    // A small threshold should trigger compaction after a few overwrites.
+    #[test]
+    fn custom_compaction_threshold() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            compaction_threshold: 100,
+            ..Default::default()
         };
-        let buf = serde_json::to_vec(&entry)?;
-        let size = buf.len() as u64;
-
-        let pos = self.writer.seek(SeekFrom::End(0))?;
-        let new_pos = size + pos;
-        self.writer.write_all(&buf)?;
-        self.writer.flush()?;
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        assert_eq!(store.compaction_threshold(), 100);
 
-        if let Some(prev) = self.index.insert(key, (self.fragment, pos..new_pos).into()) {
-            self.unreclaimed_space += prev.size;
+        // Every overwrite leaves one stale entry behind.
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let size = store.entry_disk_size("key1").unwrap();
+        let overwrites = 100 / size + 1;
+        for _ in 0..overwrites - 1 {
+            store.set("key1".to_owned(), "value1".to_owned())?;
         }
-        self.compact()
+        assert_eq!(store.active_fragment(), 0);
+        assert_eq!(store.unreclaimed_space(), (overwrites - 1) * size);
+
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.active_fragment(), 1);
+        assert_eq!(store.unreclaimed_space(), 0);
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.index.get(&key) {
-            Some(ep) => {
-                let reader = self
-                    .fragment_readers
-                    .get_mut(&self.fragment)
-                    .expect("fragment was not located");
-                reader.seek(SeekFrom::Start(ep.pos))?;
-
-                let mut buf = vec![0; ep.size];
-                reader.read_exact(&mut buf[..])?;
-
-                match serde_json::from_slice(&buf[..]) {
-                    Ok(LogEntry::Set { value, .. }) => Ok(Some(value)),
-                    // NOTE: This isn't expected; if this occurs there is something
-                    //       horribly wrong with the position or in-memory index.
-                    e => panic!("unexpected log entry at byte offset {}; {:?}", ep.pos, e),
-                }
-            }
-            None => Ok(None),
+    // Writes made while a compaction copies entries should be carried over to
+    // the compacted fragments.
+    #[test]
+    fn compaction_with_concurrent_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..4 {
+            store.set(format!("key{}", key_id), "value1".to_owned())?;
         }
-    }
+        store.set("key0".to_owned(), "value2".to_owned())?;
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.index.remove(&key) {
-            None => Err(StoreError::NotFound),
-            Some(ep) => {
-                let entry = LogEntry::Rm { key: key.clone() };
-                let buf = serde_json::to_vec(&entry)?;
+        let snapshot = store.begin_compaction(&mut store.shared.lock_log())?;
+        let copied = store.write_compacted(&snapshot);
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        let removed = store.finish_compaction(&mut store.shared.lock_log(), snapshot, copied)?;
+        assert_eq!(removed, vec![0]);
 
-                self.writer.seek(SeekFrom::End(0))?;
-                self.writer.write_all(&buf)?;
-                self.writer.flush()?;
-                self.unreclaimed_space += ep.size + buf.len();
+        let expected = BTreeMap::from([
+            ("key0".to_owned(), "value2".to_owned()),
+            ("key1".to_owned(), "value3".to_owned()),
+            ("key3".to_owned(), "value1".to_owned()),
+            ("key4".to_owned(), "value4".to_owned()),
+        ]);
+        assert_eq!(store.to_map()?, expected);
+        // The stale copies of key1 and key2, and key2's tombstone
+        assert!(store.unreclaimed_space() > 0);
 
-                self.compact()
-            }
-        }
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.to_map()?, expected);
+        Ok(())
     }
-}
-
-/// Loads the Key-Value store log fragment at the given path.
-///
-/// The process entails indexing the entries at the given path. It returns the
-/// fragment number, size of unreclaimed space and a `BufReader` for the fragment.
-fn load_fragment(
-    path: PathBuf,
-    index: &mut HashMap<String, EntryPosition>,
-) -> Result<(u64, usize, BufReader<File>)> {
-    let fragment = path
-        .file_name()
-        .and_then(|s| s.to_str())
-        .ok_or(StoreError::Fragment("invalid fragment file name".into()))?
-        .split('.')
-        .next()
-        .ok_or(StoreError::Fragment("invalid fragment file name".into()))?
-        .parse::<u64>()
-        .map_err(|_| StoreError::Fragment("invalid fragment number".into()))?;
-    let mut unreclaimed_space = 0;
 
-    let log = OpenOptions::new().read(true).open(path)?;
-    let mut reader = BufReader::new(log);
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut de = serde_json::Deserializer::from_reader(&mut reader).into_iter();
+    // Reading from many fragments should keep the reader buffers within the
+    // budget.
+    #[test]
+    fn reader_buffer_budget() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .max_fragment_bytes(200)
+            .open(temp_dir.path())?;
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.compact()?;
+        assert!(store.fragment_count() > 3);
+        drop(store);
 
-    while let Some(res) = de.next() {
-        let entry: LogEntry = res?;
-        let new_pos = de.byte_offset() as u64;
-        if let Some(prev_ep) = match entry {
-            LogEntry::Set { key, .. } => {
-                index.insert(key.to_owned(), (fragment, pos..new_pos).into())
+        let budget = 3 * 8 * 1024;
+        let store = KvStore::builder()
+            .reader_buffer_budget(budget)
+            .open(temp_dir.path())?;
+        assert!(store.reader_buffer_memory() <= budget);
+        for _ in 0..2 {
+            for key_id in 0..50 {
+                assert_eq!(
+                    store.get(format!("key{}", key_id))?,
+                    Some(format!("value{}", key_id))
+                );
+                assert!(store.reader_buffer_memory() <= budget);
             }
-            LogEntry::Rm { ref key } => index.remove(key),
-        } {
-            unreclaimed_space += prev_ep.size;
         }
-        pos = new_pos;
+        assert!(store.reader_buffer_memory() > 0);
+
+        // A budget below a single buffer still reads.
+        let store = KvStore::builder()
+            .reader_buffer_budget(0)
+            .open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.reader_buffer_memory(), 8 * 1024);
+        Ok(())
     }
 
-    Ok((fragment, unreclaimed_space, reader))
-}
+    // Large values should be stored compressed and read back transparently,
+    // across compaction and reopening.
+    #[test]
+    fn compression() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = KvStore::builder().compression(Compression::Zstd { level: 3 });
+        let mut store = builder.clone().open(temp_dir.path())?;
+        let large = "compressible ".repeat(80_000);
+        let bytes = [0xff, 0].repeat(1_000);
+        store.set("key1".to_owned(), large.clone())?;
+        store.set_bytes("key2".to_owned(), bytes.clone())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        let fragment_size = |dir: &Path| std::fs::metadata(dir.join(fragment_filename(0)));
+        assert!(fragment_size(temp_dir.path())?.len() < large.len() as u64 / 100);
+        assert_eq!(store.get("key1".to_owned())?, Some(large.clone()));
+        assert_eq!(store.get_bytes("key2".to_owned())?, Some(bytes.clone()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
 
-/// Creates a new fragment file. If file already exists it is truncated.
-fn new_fragment(fragment: u64, dir: &Path) -> Result<File> {
-    let path = dir.join(fragment_filename(fragment));
-    Ok(OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .read(true)
-        .write(true)
-        .open(path)?)
-}
+        let sizes: Vec<_> = ["key1", "key2", "key3"]
+            .iter()
+            .map(|key| store.entry_disk_size(key))
+            .collect();
+        store.compact()?;
+        let compacted: Vec<_> = ["key1", "key2", "key3"]
+            .iter()
+            .map(|key| store.entry_disk_size(key))
+            .collect();
+        assert_eq!(compacted, sizes);
+        drop(store);
 
-fn fragment_filename(fragment: u64) -> String {
-    format!("{}.{}", fragment, LOG_EXTENSION)
-}
+        // Reading compressed values does not depend on the options.
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some(large));
+        assert_eq!(store.get_bytes("key2".to_owned())?, Some(bytes));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        Ok(())
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use tempfile::TempDir;
-    use walkdir::WalkDir;
+    // Rewriting a compressed value after reopening the store should be
+    // detected as an identical write.
+    #[test]
+    fn skip_identical_compressed_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let builder = KvStore::builder()
+            .compression(Compression::Zstd { level: 3 })
+            .skip_identical_writes(true);
+        let mut store = builder.clone().open(temp_dir.path())?;
+        let large = "compressible ".repeat(1_000);
+        store.set("key1".to_owned(), large.clone())?;
+        drop(store);
 
-    // Should get previously stored value.
+        let mut store = builder.open(temp_dir.path())?;
+        let size = std::fs::metadata(temp_dir.path().join(fragment_filename(0)))?.len();
+        store.set("key1".to_owned(), large.clone())?;
+        assert_eq!(store.unreclaimed_space(), 0);
+        assert_eq!(
+            std::fs::metadata(temp_dir.path().join(fragment_filename(0)))?.len(),
+            size
+        );
+        assert_eq!(store.get("key1".to_owned())?, Some(large));
+        Ok(())
+    }
+
+    // Reads and writes on other clones should stay correct while a large
+    // compaction is in progress.
     #[test]
-    fn get_stored_value() -> Result<()> {
+    fn concurrent_compaction() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let mut store = KvStore::open(temp_dir.path())?;
+        let value = "v".repeat(1000);
+        let mut expected = BTreeMap::new();
+        for key_id in 0..5000 {
+            store.set(format!("key{}", key_id), value.clone())?;
+            expected.insert(format!("key{}", key_id), value.clone());
+        }
 
-        store.set("key1".to_owned(), "value1".to_owned())?;
-        store.set("key2".to_owned(), "value2".to_owned())?;
+        let mut compactor = store.clone();
+        let compaction = std::thread::spawn(move || compactor.compact());
+        let mut round = 0;
+        while round < 200 || !compaction.is_finished() {
+            let key = format!("key{}", round % 5000);
+            store.set(key.clone(), round.to_string())?;
+            expected.insert(key.clone(), round.to_string());
+            assert_eq!(store.get(key)?, Some(round.to_string()));
 
-        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            let removed = format!("key{}", (round * 7 + 1) % 5000);
+            if expected.remove(&removed).is_some() {
+                store.remove(removed.clone())?;
+            }
+            assert_eq!(store.get(removed)?, None);
+            round += 1;
+        }
+        compaction.join().expect("compaction thread panicked")?;
 
-        // Open from disk again and check persistent data.
+        assert!(store.compactions() >= 1);
+        assert_eq!(store.to_map()?, expected);
         drop(store);
         let mut store = KvStore::open(temp_dir.path())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.to_map()?, expected);
+        Ok(())
+    }
 
+    // Compactions triggered by writes should run in the background, and
+    // `sync` should wait for them.
+    #[test]
+    fn background_compaction() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder()
+            .compaction_threshold(1000)
+            .background_compaction(true)
+            .open(temp_dir.path())?;
+        for round in 0..500 {
+            store.set(format!("key{}", round % 10), round.to_string())?;
+        }
+        store.sync()?;
+
+        assert!(store.compactions() > 0);
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some((490 + key_id).to_string())
+            );
+        }
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key9".to_owned())?, Some("499".to_owned()));
         Ok(())
     }
 
-    // Should overwrite existent value.
+    // Opening a store whose keys were all removed should compact it down to
+    // a single empty fragment.
     #[test]
-    fn overwrite_value() -> Result<()> {
+    fn tombstone_only_store() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = KvStore::open(temp_dir.path())?;
+        let builder = KvStore::builder().compaction_threshold(usize::MAX);
+        let mut store = builder.clone().open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            store.remove(format!("key{}", key_id))?;
+        }
+        let size = std::fs::metadata(temp_dir.path().join(fragment_filename(0)))?.len();
+        drop(store);
 
-        store.set("key1".to_owned(), "value1".to_owned())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-        store.set("key1".to_owned(), "value2".to_owned())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        // Every byte past the header, tombstones included, is reclaimable.
+        let store = builder.open(temp_dir.path())?;
+        let header_len = Codec::default().header().len() as u64;
+        assert_eq!(store.unreclaimed_space() as u64, size - header_len);
+        drop(store);
 
-        // Open from disk again and check persistent data.
+        let store = KvStore::builder()
+            .compaction_threshold(1000)
+            .open(temp_dir.path())?;
+        assert_eq!(store.compactions(), 1);
+        assert!(store.is_empty());
+        assert_eq!(store.fragment_count(), 1);
+        assert_eq!(store.active_fragment(), 1);
+        assert_eq!(store.unreclaimed_space(), 0);
+        let files: Vec<_> = WalkDir::new(temp_dir.path())
+            .min_depth(1)
+            .into_iter()
+            .map(|entry| entry.map(|entry| entry.into_path()))
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(files, vec![temp_dir.path().join(fragment_filename(1))]);
+        assert_eq!(std::fs::metadata(&files[0])?.len(), header_len);
         drop(store);
-        let mut store = KvStore::open(temp_dir.path())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-        store.set("key1".to_owned(), "value3".to_owned())?;
-        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
 
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(store.is_empty());
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
         Ok(())
     }
 
-    // Should get `None` when getting a non-existent key.
+    // Rebuilding the index should undo any damage to the in-memory index and
+    // count unreclaimed space as opening the store would.
     #[test]
-    fn get_non_existent_value() -> Result<()> {
+    fn reindex() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = KvStore::open(temp_dir.path())?;
+        let builder = KvStore::builder()
+            .compaction_threshold(usize::MAX)
+            .value_cache_capacity(1_000);
+        let mut store = builder.clone().open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.set("key1".to_owned(), "value1-new".to_owned())?;
+        store.remove("key2".to_owned())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
 
-        store.set("key1".to_owned(), "value1".to_owned())?;
+        {
+            let mut index = store.shared.write_index();
+            let ep = index.namespace(DEFAULT_NAMESPACE)["key4"].clone();
+            index.insert(DEFAULT_NAMESPACE, "key3".to_owned(), ep.clone());
+            index.insert(DEFAULT_NAMESPACE, "key2".to_owned(), ep);
+            index.remove(DEFAULT_NAMESPACE, "key5");
+        }
+        store.shared.lock_log().unreclaimed_space = 0;
+        assert!(matches!(
+            store.get("key2".to_owned()),
+            Err(StoreError::Fragment(_))
+        ));
+        assert_eq!(store.get("key5".to_owned())?, None);
+
+        store.reindex()?;
+        assert_eq!(store.len(), 9);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1-new".to_owned()));
         assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+        let unreclaimed_space = store.unreclaimed_space();
+        assert!(unreclaimed_space > 0);
 
-        // Open from disk again and check persistent data.
+        // Entries written after the rebuild are indexed as usual.
+        store.set("key10".to_owned(), "value10".to_owned())?;
+        assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
         drop(store);
-        let mut store = KvStore::open(temp_dir.path())?;
-        assert_eq!(store.get("key2".to_owned())?, None);
 
+        let store = builder.open(temp_dir.path())?;
+        assert_eq!(store.unreclaimed_space(), unreclaimed_space);
+        assert_eq!(store.len(), 10);
         Ok(())
     }
 
+    // The same key should hold distinct values in every namespace, also once
+    // the store is reopened.
     #[test]
-    fn remove_non_existent_key() -> Result<()> {
+    fn namespaces() -> Result<()> {
+        for codec in [Codec::Json, Codec::Binary] {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let builder = KvStore::builder().codec(codec);
+            let mut store = builder.clone().open(temp_dir.path())?;
+            store.set("key1".to_owned(), "value1".to_owned())?;
+            store
+                .namespace("ns1")
+                .set("key1".to_owned(), "ns1-value1".to_owned())?;
+            let mut ns2 = store.namespace("ns2");
+            assert_eq!(ns2.name(), "ns2");
+            assert_eq!(ns2.get("key1".to_owned())?, None);
+            ns2.set("key1".to_owned(), "ns2-value1".to_owned())?;
+            ns2.set("key2".to_owned(), "ns2-value2".to_owned())?;
+
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            let ns1 = store.namespace("ns1");
+            assert_eq!(ns1.get("key1".to_owned())?, Some("ns1-value1".to_owned()));
+            assert_eq!(ns1.get("key2".to_owned())?, None);
+            assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1"]);
+            assert_eq!(store.len(), 1);
+
+            let mut ns1 = store.namespace("ns1");
+            ns1.remove("key1".to_owned())?;
+            assert!(matches!(
+                ns1.remove("key2".to_owned()),
+                Err(StoreError::NotFound)
+            ));
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            drop(store);
+
+            let mut store = builder.open(temp_dir.path())?;
+            assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+            assert_eq!(store.get("key2".to_owned())?, None);
+            assert_eq!(store.namespace("ns1").get("key1".to_owned())?, None);
+            let ns2 = store.namespace("ns2");
+            assert_eq!(ns2.get("key1".to_owned())?, Some("ns2-value1".to_owned()));
+            assert_eq!(ns2.get("key2".to_owned())?, Some("ns2-value2".to_owned()));
+        }
+        Ok(())
+    }
+
+    // Compaction should keep every namespace's values apart, including the
+    // removal of a key that another namespace still holds.
+    #[test]
+    fn namespaces_survive_compaction() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-        let mut store = KvStore::open(temp_dir.path())?;
-        assert!(store.remove("key1".to_owned()).is_err());
+        let builder = KvStore::builder()
+            .compaction_threshold(usize::MAX)
+            .ordered_compaction(true);
+        let mut store = builder.clone().open(temp_dir.path())?;
+        for iter in 0..10 {
+            for key_id in 0..10 {
+                let key = format!("key{}", key_id);
+                store.set(key.clone(), format!("value{}-{}", key_id, iter))?;
+                store
+                    .namespace("ns1")
+                    .set(key, format!("ns1-value{}-{}", key_id, iter))?;
+            }
+        }
+        store.namespace("ns1").remove("key0".to_owned())?;
+        store.remove("key1".to_owned())?;
+
+        store.compact()?;
+        let check = |store: &mut KvStore| -> Result<()> {
+            assert_eq!(store.get("key0".to_owned())?, Some("value0-9".to_owned()));
+            assert_eq!(store.get("key1".to_owned())?, None);
+            let ns1 = store.namespace("ns1");
+            assert_eq!(ns1.get("key0".to_owned())?, None);
+            assert_eq!(ns1.get("key1".to_owned())?, Some("ns1-value1-9".to_owned()));
+            for key_id in 2..10 {
+                let key = format!("key{}", key_id);
+                assert_eq!(store.get(key.clone())?, Some(format!("value{}-9", key_id)));
+                assert_eq!(
+                    store.namespace("ns1").get(key)?,
+                    Some(format!("ns1-value{}-9", key_id))
+                );
+            }
+            Ok(())
+        };
+        check(&mut store)?;
+        assert_eq!(store.unreclaimed_space(), 0);
+        drop(store);
+
+        let mut store = builder.open(temp_dir.path())?;
+        check(&mut store)?;
+        assert_eq!(store.unreclaimed_space(), 0);
         Ok(())
     }
 
+    // Disk size should cover the whole entry, not just the value.
     #[test]
-    fn remove_key() -> Result<()> {
+    fn entry_disk_size() -> Result<()> {
         let temp_dir = TempDir::new().expect("unable to create temporary working directory");
         let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.entry_disk_size("key1"), None);
+
         store.set("key1".to_owned(), "value1".to_owned())?;
-        assert!(store.remove("key1".to_owned()).is_ok());
-        assert_eq!(store.get("key1".to_owned())?, None);
+        // Tag byte, two length prefixes and the checksum.
+        let framing = 1 + 4 + 4 + 4;
+        assert_eq!(store.entry_disk_size("key1"), Some(framing + 4 + 6));
+
+        // Open from disk again and check the indexed size.
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.entry_disk_size("key1"), Some(framing + 4 + 6));
+
         Ok(())
     }
 
@@ -403,7 +5216,7 @@ mod test {
 
             drop(store);
             // reopen and check content.
-            let mut store = KvStore::open(temp_dir.path())?;
+            let store = KvStore::open(temp_dir.path())?;
             for key_id in 0..1000 {
                 let key = format!("key{}", key_id);
                 assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -2,10 +2,21 @@
 //!
 //! Storage engines handle how data is stored, read and represented on disk.
 
-use tracing::subscriber::SetGlobalDefaultError;
+use std::{ops::Bound, time::Duration};
+mod cache;
+mod codec;
+mod index;
 pub mod kvs;
+pub mod routing;
+pub mod sled;
 
-pub use kvs::KvStore;
+pub use self::sled::SledKvEngine;
+pub use codec::Codec;
+pub use kvs::{
+    Compression, ConflictPolicy, KvStore, KvStoreBuilder, KvStoreOptions, NamespaceHandle,
+    SyncPolicy,
+};
+pub use routing::RoutingEngine;
 
 /// Custom `Result` type that represents a success or error of KvStore
 /// functionality
@@ -13,8 +24,9 @@ pub type Result<T> = std::result::Result<T, StoreError>;
 
 /// Key-Value storage engine trait.
 ///
-/// Defines the interface used to interact with storage engines
-pub trait KvEngine {
+/// Defines the interface used to interact with storage engines. Engines are
+/// `Send` so a server can hand them to the thread serving its connections.
+pub trait KvEngine: Send {
     /// Set the value of a key.
     fn set(&mut self, key: String, value: String) -> Result<()>;
 
@@ -27,6 +39,132 @@ pub trait KvEngine {
     ///
     /// An error is returned if the key does not exist.
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// # Errors
+    ///
+    /// The default implementation returns `StoreError::Unsupported` for engines
+    /// that can not expire keys.
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let _ = (key, value, ttl);
+        Err(StoreError::Unsupported("ttl"))
+    }
+
+    /// Sets the value of a key only if the key does not exist.
+    ///
+    /// Returns whether the value was set. The default implementations of the
+    /// conditional operations check and write in separate steps, relying on
+    /// `&mut self` for exclusive access to the engine. Engines whose handles
+    /// share their data must override them to check and write atomically.
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        if self.get(key.clone())?.is_some() {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Replaces the value of a key only if its current value equals
+    /// `expected`.
+    ///
+    /// Returns whether the value was replaced. A missing key never matches.
+    fn compare_and_swap(&mut self, key: String, expected: String, new: String) -> Result<bool> {
+        if self.get(key.clone())?.as_ref() != Some(&expected) {
+            return Ok(false);
+        }
+        self.set(key, new)?;
+        Ok(true)
+    }
+
+    /// Removes a key only if its current value equals `expected`.
+    ///
+    /// Returns whether the key was removed. A missing key never matches.
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        if self.get(key.clone())?.as_ref() != Some(&expected) {
+            return Ok(false);
+        }
+        self.remove(key)?;
+        Ok(true)
+    }
+
+    /// Returns all key-value pairs whose key starts with `prefix`, sorted by
+    /// key.
+    ///
+    /// # Errors
+    ///
+    /// The default implementation returns `StoreError::Unsupported` for engines
+    /// that can not scan their keys.
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let _ = prefix;
+        Err(StoreError::Unsupported("scan"))
+    }
+
+    /// Returns all key-value pairs whose key lies within the given bounds,
+    /// sorted by key.
+    ///
+    /// # Errors
+    ///
+    /// The default implementation returns `StoreError::Unsupported` for engines
+    /// that can not scan their keys.
+    fn range(&mut self, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<(String, String)>> {
+        let _ = (start, end);
+        Err(StoreError::Unsupported("range"))
+    }
+
+    /// Makes all previous writes durable.
+    ///
+    /// The default implementation does nothing, for engines that persist every
+    /// write before returning.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the optional operations the engine supports.
+    ///
+    /// The default implementation reports only the conditional operations,
+    /// which have default implementations.
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities {
+            cas: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns statistics about the engine's contents and maintenance work.
+    ///
+    /// The default implementation reports nothing, for engines that do not
+    /// track any statistics.
+    fn stats(&mut self) -> EngineStats {
+        EngineStats::default()
+    }
+}
+
+/// Optional operations supported by a storage engine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineCapabilities {
+    /// Scanning keys by prefix or range
+    pub scan: bool,
+    /// Setting keys that expire
+    pub ttl: bool,
+    /// Conditional writes: set if absent, compare-and-swap and remove if
+    /// equal
+    pub cas: bool,
+    /// Explicitly compacting the engine's storage
+    pub compaction: bool,
+    /// Writing many keys at once
+    pub batch: bool,
+}
+
+/// Statistics reported by a storage engine
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// Number of keys currently stored
+    pub live_keys: u64,
+    /// Number of compactions run since the engine was opened
+    pub compactions: u64,
+    /// Space reclaimed by those compactions, in bytes
+    pub reclaimed_space: u64,
 }
 
 /// The error type for StorageEngine operations.
@@ -41,12 +179,33 @@ pub enum StoreError {
     NotFound,
     /// An error occurred while accessing a log fragment
     Fragment(String),
-
-    // TODO: Everything from this point needs to move; It's not related to the storage engines
-    /// An error occurred while setting default tracing subscriber
-    SubscriberGlobalDefault(SetGlobalDefaultError),
-    /// An error occurred during address parsing
-    AddrParse(std::net::AddrParseError),
+    /// An error occurred in the sled storage engine
+    Sled(::sled::Error),
+    /// A stored key or value is not valid UTF-8
+    Utf8(std::string::FromUtf8Error),
+    /// The operation is not supported by the storage engine
+    Unsupported(&'static str),
+    /// The store was written with a different codec than the one requested
+    CodecMismatch {
+        /// Codec that was requested
+        requested: Codec,
+        /// Codec the existing fragments were written with
+        found: Codec,
+    },
+    /// The data directory holds data written by a different storage engine
+    EngineMismatch {
+        /// Engine that was requested
+        requested: String,
+        /// Engine that wrote the existing data
+        found: String,
+    },
+    /// A log entry is larger than the store's maximum entry size
+    EntryTooLarge {
+        /// Size of the entry in bytes
+        size: usize,
+        /// Maximum entry size in bytes
+        max: usize,
+    },
 }
 
 impl std::fmt::Display for StoreError {
@@ -56,10 +215,24 @@ impl std::fmt::Display for StoreError {
             StoreError::NotFound => write!(f, "Key not found"),
             StoreError::Serde(err) => write!(f, "Serde error: {}", err),
             StoreError::Fragment(desc) => write!(f, "Fragment error: {}", desc),
-            StoreError::SubscriberGlobalDefault(err) => {
-                write!(f, "Tracing subscriber error: {}", err)
-            }
-            StoreError::AddrParse(err) => write!(f, "Address parsing error: {}", err),
+            StoreError::Sled(err) => write!(f, "Sled error: {}", err),
+            StoreError::Utf8(err) => write!(f, "UTF-8 error: {}", err),
+            StoreError::Unsupported(op) => write!(f, "Unsupported operation: {}", op),
+            StoreError::CodecMismatch { requested, found } => write!(
+                f,
+                "Codec mismatch: requested {:?} but fragments were written with {:?}",
+                requested, found
+            ),
+            StoreError::EngineMismatch { requested, found } => write!(
+                f,
+                "Engine mismatch: requested {} but data was written by {}",
+                requested, found
+            ),
+            StoreError::EntryTooLarge { size, max } => write!(
+                f,
+                "Entry too large: {} bytes exceeds the maximum entry size of {} bytes",
+                size, max
+            ),
         }
     }
 }
@@ -71,8 +244,12 @@ impl std::error::Error for StoreError {
             StoreError::NotFound => None,
             StoreError::Serde(err) => Some(err),
             StoreError::Fragment(_) => None,
-            StoreError::SubscriberGlobalDefault(err) => Some(err),
-            StoreError::AddrParse(err) => Some(err),
+            StoreError::Sled(err) => Some(err),
+            StoreError::Utf8(err) => Some(err),
+            StoreError::Unsupported(_) => None,
+            StoreError::CodecMismatch { .. } => None,
+            StoreError::EngineMismatch { .. } => None,
+            StoreError::EntryTooLarge { .. } => None,
         }
     }
 }
@@ -89,14 +266,14 @@ impl From<serde_json::error::Error> for StoreError {
     }
 }
 
-impl From<SetGlobalDefaultError> for StoreError {
-    fn from(err: SetGlobalDefaultError) -> Self {
-        Self::SubscriberGlobalDefault(err)
+impl From<::sled::Error> for StoreError {
+    fn from(err: ::sled::Error) -> Self {
+        Self::Sled(err)
     }
 }
 
-impl From<std::net::AddrParseError> for StoreError {
-    fn from(err: std::net::AddrParseError) -> Self {
-        Self::AddrParse(err)
+impl From<std::string::FromUtf8Error> for StoreError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        Self::Utf8(err)
     }
 }
@@ -0,0 +1,253 @@
+//! Storage engine that routes keys across multiple sub-engines
+//!
+use super::{EngineCapabilities, EngineStats, KvEngine, Result};
+use std::{ops::Bound, time::Duration};
+
+/// Function selecting which sub-engine handles a key.
+pub type Router = Box<dyn Fn(&str) -> usize + Send>;
+
+/// Composes multiple storage engines, dispatching every operation to the
+/// engine selected by a routing function.
+///
+/// This allows keeping e.g. hot keys in one engine while storing the bulk of
+/// the data in another. A key is always routed to exactly one engine, so
+/// operations spanning multiple keys (like `scan`) query every engine and
+/// merge the results.
+pub struct RoutingEngine {
+    engines: Vec<Box<dyn KvEngine>>,
+    router: Router,
+}
+
+impl RoutingEngine {
+    /// Creates a routing engine from a list of sub-engines and a routing
+    /// function returning the index of the engine that should handle a key.
+    pub fn new(engines: Vec<Box<dyn KvEngine>>, router: Router) -> Self {
+        Self { engines, router }
+    }
+
+    /// Returns every key of every sub-engine, sorted.
+    ///
+    /// Sub-engines are listed with `scan`, so every one of them must support
+    /// it; otherwise `StoreError::Unsupported` is returned.
+    pub fn keys(&mut self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for engine in self.engines.iter_mut() {
+            keys.extend(engine.scan("")?.into_iter().map(|(key, _)| key));
+        }
+        keys.sort_unstable();
+        Ok(keys)
+    }
+
+    /// Returns the engine responsible for the given key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the routing function returns an index that is out of range.
+    fn route(&mut self, key: &str) -> &mut dyn KvEngine {
+        let idx = (self.router)(key);
+        let len = self.engines.len();
+        self.engines
+            .get_mut(idx)
+            .unwrap_or_else(|| panic!("routed key to engine {} out of {}", idx, len))
+            .as_mut()
+    }
+}
+
+impl KvEngine for RoutingEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.route(&key).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.route(&key).get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.route(&key).remove(key)
+    }
+
+    fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.route(&key).set_with_ttl(key, value, ttl)
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.route(&key).set_nx(key, value)
+    }
+
+    fn compare_and_swap(&mut self, key: String, expected: String, new: String) -> Result<bool> {
+        self.route(&key).compare_and_swap(key, expected, new)
+    }
+
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.route(&key).remove_if(key, expected)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for engine in self.engines.iter_mut() {
+            pairs.extend(engine.scan(prefix)?);
+        }
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    fn range(&mut self, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for engine in self.engines.iter_mut() {
+            pairs.extend(engine.range(start, end)?);
+        }
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.engines
+            .iter_mut()
+            .try_for_each(|engine| engine.flush())
+    }
+
+    /// Reports the operations every sub-engine supports. Compaction and
+    /// batches are not forwarded to the sub-engines.
+    fn capabilities(&self) -> EngineCapabilities {
+        self.engines
+            .iter()
+            .map(|engine| engine.capabilities())
+            .fold(
+                EngineCapabilities {
+                    scan: true,
+                    ttl: true,
+                    cas: true,
+                    ..Default::default()
+                },
+                |all, engine| EngineCapabilities {
+                    scan: all.scan && engine.scan,
+                    ttl: all.ttl && engine.ttl,
+                    cas: all.cas && engine.cas,
+                    ..all
+                },
+            )
+    }
+
+    fn stats(&mut self) -> EngineStats {
+        self.engines.iter_mut().map(|engine| engine.stats()).fold(
+            EngineStats::default(),
+            |total, stats| EngineStats {
+                live_keys: total.live_keys + stats.live_keys,
+                compactions: total.compactions + stats.compactions,
+                reclaimed_space: total.reclaimed_space + stats.reclaimed_space,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::KvStore;
+    use tempfile::TempDir;
+
+    fn cache_router() -> Router {
+        Box::new(|key| if key.starts_with("cache:") { 0 } else { 1 })
+    }
+
+    // Keys should be stored in the engine selected by the router.
+    #[test]
+    fn routes_keys_to_engines() -> Result<()> {
+        let cache_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bulk_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = RoutingEngine::new(
+            vec![
+                Box::new(KvStore::open(cache_dir.path())?),
+                Box::new(KvStore::open(bulk_dir.path())?),
+            ],
+            cache_router(),
+        );
+
+        engine.set("cache:key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(
+            engine.get("cache:key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        // Open each engine on its own and check placement.
+        drop(engine);
+        let cache = KvStore::open(cache_dir.path())?;
+        let bulk = KvStore::open(bulk_dir.path())?;
+        assert_eq!(
+            cache.get("cache:key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(cache.get("key2".to_owned())?, None);
+        assert_eq!(bulk.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(bulk.get("cache:key1".to_owned())?, None);
+
+        Ok(())
+    }
+
+    // Scan should merge the results of every engine.
+    #[test]
+    fn scan_merges_engines() -> Result<()> {
+        let cache_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bulk_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = RoutingEngine::new(
+            vec![
+                Box::new(KvStore::open(cache_dir.path())?),
+                Box::new(KvStore::open(bulk_dir.path())?),
+            ],
+            cache_router(),
+        );
+
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        engine.set("cache:key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(
+            engine.scan("")?,
+            vec![
+                ("cache:key1".to_owned(), "value1".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+            ]
+        );
+        Ok(())
+    }
+
+    // Keys should list the keys of every engine, sorted.
+    #[test]
+    fn keys_merges_engines() -> Result<()> {
+        let cache_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bulk_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = RoutingEngine::new(
+            vec![
+                Box::new(KvStore::open(cache_dir.path())?),
+                Box::new(KvStore::open(bulk_dir.path())?),
+            ],
+            cache_router(),
+        );
+
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        engine.set("cache:key3".to_owned(), "value3".to_owned())?;
+        engine.set("cache:key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(engine.keys()?, vec!["cache:key1", "cache:key3", "key2"]);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_routed_key() -> Result<()> {
+        let cache_dir = TempDir::new().expect("unable to create temporary working directory");
+        let bulk_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = RoutingEngine::new(
+            vec![
+                Box::new(KvStore::open(cache_dir.path())?),
+                Box::new(KvStore::open(bulk_dir.path())?),
+            ],
+            cache_router(),
+        );
+
+        engine.set("cache:key1".to_owned(), "value1".to_owned())?;
+        assert!(engine.remove("key1".to_owned()).is_err());
+        assert!(engine.remove("cache:key1".to_owned()).is_ok());
+        assert_eq!(engine.get("cache:key1".to_owned())?, None);
+
+        Ok(())
+    }
+}
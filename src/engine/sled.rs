@@ -0,0 +1,213 @@
+//! Storage engine backed by the sled embedded database
+//!
+use std::{ops::Bound, path::PathBuf};
+
+use super::{EngineCapabilities, EngineStats, KvEngine, Result, StoreError};
+
+/// Key-value storage engine wrapping a [`sled::Db`].
+///
+/// Every write is flushed to disk before returning, matching the durability
+/// of `KvStore`.
+pub struct SledKvEngine {
+    db: sled::Db,
+}
+
+impl SledKvEngine {
+    /// Opens the sled database in the given directory, creating it if it does
+    /// not exist.
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            db: sled::open(dir.into())?,
+        })
+    }
+}
+
+impl SledKvEngine {
+    /// Atomically replaces the value of a key if it currently holds
+    /// `expected`, where `None` stands for a missing key.
+    ///
+    /// Returns whether the value was replaced.
+    fn swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<bool> {
+        let swapped = self
+            .db
+            .compare_and_swap(key, expected, new.map(String::into_bytes))?
+            .is_ok();
+        if swapped {
+            self.db.flush()?;
+        }
+        Ok(swapped)
+    }
+}
+
+impl KvEngine for SledKvEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.db
+            .get(key)?
+            .map(|value| String::from_utf8(value.to_vec()).map_err(Into::into))
+            .transpose()
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(StoreError::NotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        self.swap(key, None, Some(value))
+    }
+
+    fn compare_and_swap(&mut self, key: String, expected: String, new: String) -> Result<bool> {
+        self.swap(key, Some(expected), Some(new))
+    }
+
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.swap(key, Some(expected), None)
+    }
+
+    fn scan(&mut self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.db.scan_prefix(prefix).map(decode_pair).collect()
+    }
+
+    fn range(&mut self, start: Bound<&str>, end: Bound<&str>) -> Result<Vec<(String, String)>> {
+        self.db
+            .range::<&str, _>((start, end))
+            .map(decode_pair)
+            .collect()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn capabilities(&self) -> EngineCapabilities {
+        EngineCapabilities {
+            scan: true,
+            cas: true,
+            ..Default::default()
+        }
+    }
+
+    fn stats(&mut self) -> EngineStats {
+        // sled compacts its own storage and does not report it.
+        EngineStats {
+            live_keys: self.db.len() as u64,
+            ..Default::default()
+        }
+    }
+}
+
+/// Converts a key-value pair read from sled into strings.
+fn decode_pair(pair: sled::Result<(sled::IVec, sled::IVec)>) -> Result<(String, String)> {
+    let (key, value) = pair?;
+    Ok((
+        String::from_utf8(key.to_vec())?,
+        String::from_utf8(value.to_vec())?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Should get previously stored value.
+    #[test]
+    fn get_stored_value() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = SledKvEngine::open(temp_dir.path())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = SledKvEngine::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        Ok(())
+    }
+
+    // Conditional writes should only apply when the current value matches.
+    #[test]
+    fn conditional_writes() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = SledKvEngine::open(temp_dir.path())?;
+
+        assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+        assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+        assert!(!store.compare_and_swap(
+            "key1".to_owned(),
+            "value2".to_owned(),
+            "value3".to_owned()
+        )?);
+        assert!(store.compare_and_swap(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            "value3".to_owned()
+        )?);
+        assert!(!store.compare_and_swap(
+            "key2".to_owned(),
+            "value1".to_owned(),
+            "value3".to_owned()
+        )?);
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        assert!(!store.remove_if("key1".to_owned(), "value1".to_owned())?);
+        assert!(store.remove_if("key1".to_owned(), "value3".to_owned())?);
+        assert!(!store.remove_if("key1".to_owned(), "value3".to_owned())?);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    }
+
+    // Should remove a key.
+    #[test]
+    fn remove_key() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = SledKvEngine::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(store.remove("key1".to_owned()).is_ok());
+        assert_eq!(store.get("key1".to_owned())?, None);
+
+        // Removing a missing key should fail like it does for KvStore.
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(StoreError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = SledKvEngine::open(temp_dir.path())?;
+        store.set("user:2".to_owned(), "bob".to_owned())?;
+        store.set("user:1".to_owned(), "alice".to_owned())?;
+        store.set("group:1".to_owned(), "admins".to_owned())?;
+
+        assert_eq!(
+            store.scan("user:")?,
+            vec![
+                ("user:1".to_owned(), "alice".to_owned()),
+                ("user:2".to_owned(), "bob".to_owned()),
+            ]
+        );
+        assert_eq!(
+            store.range(Bound::Excluded("group:1"), Bound::Included("user:1"))?,
+            vec![("user:1".to_owned(), "alice".to_owned())]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Errors of the key-value server, client and their command line tools
+//!
+//! Storage engines report [`StoreError`]s; everything built on top of them,
+//! like the network protocol and the binaries, reports [`KvsError`]s, which
+//! wrap storage errors as they are.
+use tracing::subscriber::SetGlobalDefaultError;
+
+use crate::{engine::StoreError, protocol::Response};
+
+/// Custom `Result` type that represents a success or error of the key-value
+/// server and client
+pub type Result<T> = std::result::Result<T, KvsError>;
+
+/// The error type for the networking layer and command line tools.
+#[derive(Debug)]
+pub enum KvsError {
+    /// The storage engine failed.
+    Store(StoreError),
+    /// An IO error occurred while accessing a socket or the terminal.
+    Io(std::io::Error),
+    /// A protocol message could not be serialized or deserialized.
+    Serde(serde_json::error::Error),
+    /// An error occurred while setting default tracing subscriber
+    SubscriberGlobalDefault(SetGlobalDefaultError),
+    /// An error occurred during address parsing
+    AddrParse(std::net::AddrParseError),
+    /// The server failed to apply a request.
+    Server(String),
+    /// The server's storage engine does not support the requested operation.
+    Unsupported(String),
+    /// The server answered a request with a response that does not belong
+    /// to it.
+    UnexpectedResponse(Response),
+}
+
+impl std::fmt::Display for KvsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvsError::Store(err) => write!(f, "{}", err),
+            KvsError::Io(err) => write!(f, "IO error: {}", err),
+            KvsError::Serde(err) => write!(f, "Protocol error: {}", err),
+            KvsError::SubscriberGlobalDefault(err) => {
+                write!(f, "Tracing subscriber error: {}", err)
+            }
+            KvsError::AddrParse(err) => write!(f, "Address parsing error: {}", err),
+            KvsError::Server(err) => write!(f, "{}", err),
+            KvsError::Unsupported(capability) => write!(
+                f,
+                "Unsupported by the server's storage engine: {}",
+                capability
+            ),
+            KvsError::UnexpectedResponse(response) => {
+                write!(f, "Unexpected response: {:?}", response)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KvsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvsError::Store(err) => Some(err),
+            KvsError::Io(err) => Some(err),
+            KvsError::Serde(err) => Some(err),
+            KvsError::SubscriberGlobalDefault(err) => Some(err),
+            KvsError::AddrParse(err) => Some(err),
+            KvsError::Server(_) => None,
+            KvsError::Unsupported(_) => None,
+            KvsError::UnexpectedResponse(_) => None,
+        }
+    }
+}
+
+impl From<StoreError> for KvsError {
+    fn from(err: StoreError) -> Self {
+        Self::Store(err)
+    }
+}
+
+impl From<std::io::Error> for KvsError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::error::Error> for KvsError {
+    fn from(err: serde_json::error::Error) -> Self {
+        Self::Serde(err)
+    }
+}
+
+impl From<SetGlobalDefaultError> for KvsError {
+    fn from(err: SetGlobalDefaultError) -> Self {
+        Self::SubscriberGlobalDefault(err)
+    }
+}
+
+impl From<std::net::AddrParseError> for KvsError {
+    fn from(err: std::net::AddrParseError) -> Self {
+        Self::AddrParse(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error;
+
+    // Storage errors should pass through unchanged, and networking errors
+    // should convert without involving the storage error type.
+    #[test]
+    fn conversions() {
+        let err = KvsError::from(StoreError::NotFound);
+        assert!(matches!(err, KvsError::Store(StoreError::NotFound)));
+        assert_eq!(err.to_string(), "Key not found");
+        assert!(err.source().is_some());
+
+        let parse_err = "not an address"
+            .parse::<std::net::SocketAddr>()
+            .unwrap_err();
+        let err = KvsError::from(parse_err);
+        assert!(matches!(err, KvsError::AddrParse(_)));
+        assert!(err.to_string().starts_with("Address parsing error"));
+
+        // Every storage error is a storage concern.
+        let store_err = |err: &StoreError| match err {
+            StoreError::Io(_)
+            | StoreError::Serde(_)
+            | StoreError::NotFound
+            | StoreError::Fragment(_)
+            | StoreError::Sled(_)
+            | StoreError::Utf8(_)
+            | StoreError::Unsupported(_)
+            | StoreError::CodecMismatch { .. }
+            | StoreError::EngineMismatch { .. }
+            | StoreError::EntryTooLarge { .. } => true,
+        };
+        assert!(store_err(&StoreError::NotFound));
+    }
+}
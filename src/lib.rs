@@ -6,38 +6,351 @@
 //! keys and values.
 //!
 //! The key-value database implementation utilizes a log-structured store.
+pub mod client;
 pub mod engine;
+pub mod error;
+pub mod metrics;
+pub mod protocol;
+pub mod thread_pool;
 
-use std::{fmt::Display, net::TcpStream};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{self, BufReader, BufWriter},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::Path,
+    sync::{
+        mpsc::{Receiver, TryRecvError},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::Duration,
+};
 
-// TODO: This needs to be split; Engine errors are different from the network
-//       bits.
-pub use engine::Result;
-
-// TODO: Network Protocol, KvClient, KvServer
+pub use client::KvsClient;
+pub use error::{KvsError, Result};
 
+use engine::{EngineCapabilities, KvEngine, KvStore, SledKvEngine, StoreError};
+pub use metrics::Metrics;
+use protocol::{Request, Response, TransactionOp};
 use serde::Serialize;
-use tracing::{info, instrument};
+use thread_pool::ThreadPool;
+use tracing::{debug, error, info, instrument};
+
+/// Storage engine shared by the connections of a server
+type SharedEngine = Arc<Mutex<Box<dyn KvEngine>>>;
+
+/// How long the server waits between polls for new connections
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// Implements the core functionality of a Key-Value Server
-pub struct KvServer {}
-
-impl KvServer {
-    /// Create a key-value server
-    pub fn new() -> Self {
-        Self {}
-    }
-
-    /// Handle an incoming client connection
-    //TODO: The client field is a bit sketchy. I can probably do this within the
-    // function body and actually handle the error; just create a new
-    // info_span... Keeping this here since i'm still not sure how to structure
-    // this
-    #[instrument(level = "info", skip_all, fields(client = stream.peer_addr().unwrap().to_string()))]
-    pub fn handle_connection(&mut self, stream: TcpStream) -> Result<()> {
-        info!(target: "connection", "accepted connection");
+///
+/// Connections are served concurrently on a thread pool; requests are applied
+/// to the storage engine one at a time.
+pub struct KvServer<P: ThreadPool> {
+    engine: SharedEngine,
+    pool: P,
+    connections: Arc<Connections>,
+    metrics: Arc<Metrics>,
+}
+
+/// Connections currently being served
+#[derive(Default)]
+struct Connections {
+    /// Handles to the open connections, keyed by connection id
+    open: Mutex<HashMap<u64, TcpStream>>,
+    /// Signalled whenever a connection is closed
+    closed: Condvar,
+}
+
+impl Connections {
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, TcpStream>> {
+        self.open.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Removes a connection from the open connections when it is dropped, even if
+/// serving it panicked
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.lock().remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+impl<P: ThreadPool> KvServer<P> {
+    /// Create a key-value server serving requests from the given storage
+    /// engine on the given thread pool
+    pub fn new(engine: Box<dyn KvEngine>, pool: P) -> Self {
+        Self {
+            engine: Arc::new(Mutex::new(engine)),
+            pool,
+            connections: Arc::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Returns the server's metrics, refreshing the storage engine's
+    /// statistics first
+    pub fn metrics(&self) -> &Metrics {
+        let stats = self
+            .engine
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .stats();
+        self.metrics.update_engine(stats);
+        &self.metrics
+    }
+
+    /// Accept connections from the listener, handling each one on the thread
+    /// pool, until `shutdown` receives a message or its sender is dropped
+    ///
+    /// On shutdown the server stops accepting connections, stops reading new
+    /// requests from the open ones, waits for them to finish the requests in
+    /// progress and flushes the storage engine before returning.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the listener can not be polled or the engine can
+    /// not be flushed.
+    pub fn run(&self, listener: TcpListener, shutdown: Receiver<()>) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let mut next_id = 0;
+        while let Err(TryRecvError::Empty) = shutdown.try_recv() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(err) = self.spawn_connection(next_id, stream) {
+                        error!(target: "connection", error = %err, "connection failed");
+                    }
+                    next_id += 1;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL)
+                }
+                Err(err) => error!(target: "connection", error = %err, "connection failed"),
+            }
+        }
+
+        info!(target: "shutdown", "shutting down");
+        let mut open = self.connections.lock();
+        for stream in open.values() {
+            // Ends the connection's stream of requests; responses can still be
+            // written.
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !open.is_empty() {
+            open = self
+                .connections
+                .closed
+                .wait(open)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        drop(open);
+
+        self.engine
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush()?;
+        Ok(())
+    }
+
+    /// Register a connection and serve it on the thread pool
+    fn spawn_connection(&self, id: u64, stream: TcpStream) -> Result<()> {
+        // Accepted streams inherit the listener's non-blocking mode.
+        stream.set_nonblocking(false)?;
+        self.connections.lock().insert(id, stream.try_clone()?);
+        let guard = ConnectionGuard {
+            connections: Arc::clone(&self.connections),
+            id,
+        };
+        let engine = Arc::clone(&self.engine);
+        let metrics = Arc::clone(&self.metrics);
+        self.pool.spawn(move || {
+            let _guard = guard;
+            if let Err(err) = handle_connection(&engine, &metrics, stream) {
+                error!(target: "connection", error = %err, "connection failed");
+            }
+        });
         Ok(())
     }
+
+    /// Handle an incoming client connection on the calling thread
+    ///
+    /// Requests are read and answered in order until the client closes the
+    /// connection.
+    pub fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        handle_connection(&self.engine, &self.metrics, stream)
+    }
+}
+
+//TODO: The client field is a bit sketchy. I can probably do this within the
+// function body and actually handle the error; just create a new
+// info_span... Keeping this here since i'm still not sure how to structure
+// this
+#[instrument(level = "info", skip_all, fields(client = stream.peer_addr().unwrap().to_string()))]
+fn handle_connection(engine: &SharedEngine, metrics: &Metrics, stream: TcpStream) -> Result<()> {
+    info!(target: "connection", "accepted connection");
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(request) = protocol::receive(&mut reader)? {
+        debug!(target: "connection", ?request, "received request");
+        metrics.record(&request);
+        let response = handle_request(engine, metrics, request);
+        protocol::send(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+/// Apply a single request to the storage engine
+fn handle_request(engine: &SharedEngine, metrics: &Metrics, request: Request) -> Response {
+    // A request that panicked must not take every other connection down with
+    // it by poisoning the lock.
+    let mut engine = engine.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(capability) = missing_capability(&request, engine.capabilities()) {
+        return Response::Unsupported(capability.to_owned());
+    }
+    let result = match request {
+        Request::Get { key } => engine.get(key).map(Response::Value),
+        Request::Set { key, value } => engine.set(key, value).map(|_| Response::Ok),
+        Request::Rm { key } => engine.remove(key).map(|_| Response::Ok),
+        Request::RmIfExists { key } => match engine.remove(key) {
+            Ok(()) => Ok(Response::Success(true)),
+            Err(StoreError::NotFound) => Ok(Response::Success(false)),
+            Err(err) => Err(err),
+        },
+        Request::SetWithTtl { key, value, ttl_ms } => engine
+            .set_with_ttl(key, value, Duration::from_millis(ttl_ms))
+            .map(|_| Response::Ok),
+        Request::SetNx { key, value } => engine.set_nx(key, value).map(Response::Success),
+        Request::Cas { key, expected, new } => engine
+            .compare_and_swap(key, expected, new)
+            .map(Response::Success),
+        Request::RemoveIf { key, expected } => {
+            engine.remove_if(key, expected).map(Response::Success)
+        }
+        Request::Metrics => {
+            metrics.update_engine(engine.stats());
+            Ok(Response::Value(Some(metrics.render())))
+        }
+        Request::Transaction { ops } => apply_transaction(engine.as_mut(), ops),
+    };
+    result.unwrap_or_else(|err| match err {
+        StoreError::Unsupported(op) => Response::Unsupported(op.to_owned()),
+        err => Response::Err(err.to_string()),
+    })
+}
+
+/// Apply the operations of a transaction, all or none
+///
+/// Writes are staged until every operation succeeded, and reads see the
+/// staged writes. The caller holds the engine lock throughout, so no other
+/// request interleaves with the transaction. If the engine fails while
+/// applying the staged writes, the ones already applied are rolled back to
+/// the values the keys held before.
+fn apply_transaction(
+    engine: &mut dyn KvEngine,
+    ops: Vec<TransactionOp>,
+) -> engine::Result<Response> {
+    // Staged writes, `None` for removals, and the keys in order of first write
+    let mut staged: HashMap<String, Option<String>> = HashMap::new();
+    let mut written = Vec::new();
+    let mut results = Vec::with_capacity(ops.len());
+    let read = |engine: &mut dyn KvEngine, staged: &HashMap<String, Option<String>>, key: &str| {
+        match staged.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => engine.get(key.to_owned()),
+        }
+    };
+
+    for op in ops {
+        let (result, write) = match op {
+            TransactionOp::Get { key } => {
+                (Response::Value(read(&mut *engine, &staged, &key)?), None)
+            }
+            TransactionOp::Set { key, value } => (Response::Ok, Some((key, Some(value)))),
+            TransactionOp::Rm { key } => match read(&mut *engine, &staged, &key)? {
+                Some(_) => (Response::Ok, Some((key, None))),
+                None => (Response::Err(StoreError::NotFound.to_string()), None),
+            },
+            TransactionOp::Check { key, expected } => {
+                let holds = read(&mut *engine, &staged, &key)? == expected;
+                (Response::Success(holds), None)
+            }
+        };
+        let failed = matches!(result, Response::Err(_) | Response::Success(false));
+        results.push(result);
+        if failed {
+            return Ok(Response::Transaction {
+                committed: false,
+                results,
+            });
+        }
+        if let Some((key, value)) = write {
+            if staged.insert(key.clone(), value).is_none() {
+                written.push(key);
+            }
+        }
+    }
+
+    // Values the keys held before the transaction, for rolling it back
+    let mut previous = Vec::with_capacity(written.len());
+    for key in written {
+        let value = staged.remove(&key).flatten();
+        let res = engine.get(key.clone()).and_then(|prior| {
+            previous.push((key.clone(), prior));
+            write_value(engine, key, value)
+        });
+        if let Err(err) = res {
+            // Restore in reverse order, including the key that failed, which
+            // the engine may have written anyway.
+            for (key, prior) in previous.into_iter().rev() {
+                if let Err(err) = write_value(engine, key.clone(), prior) {
+                    error!(target: "transaction", key, error = %err, "failed to roll back write");
+                }
+            }
+            return Err(err);
+        }
+    }
+    Ok(Response::Transaction {
+        committed: true,
+        results,
+    })
+}
+
+/// Sets a key to `value`, or removes it if `value` is `None`
+fn write_value(
+    engine: &mut dyn KvEngine,
+    key: String,
+    value: Option<String>,
+) -> engine::Result<()> {
+    match value {
+        Some(value) => engine.set(key, value),
+        // The key may only have been set within the transaction.
+        None => match engine.remove(key) {
+            Err(StoreError::NotFound) => Ok(()),
+            res => res,
+        },
+    }
+}
+
+/// Returns the capability a request needs that the storage engine lacks, if
+/// any
+fn missing_capability(request: &Request, capabilities: EngineCapabilities) -> Option<&'static str> {
+    match request {
+        Request::SetWithTtl { .. } if !capabilities.ttl => Some("ttl"),
+        Request::SetNx { .. } | Request::Cas { .. } | Request::RemoveIf { .. }
+            if !capabilities.cas =>
+        {
+            Some("cas")
+        }
+        _ => None,
+    }
 }
 
 /// List of supported storage engines
@@ -51,6 +364,41 @@ pub enum EngineType {
     Sled,
 }
 
+/// Name of the file recording which engine owns a data directory
+pub const ENGINE_MARKER: &str = "engine";
+
+impl EngineType {
+    /// Opens the storage engine in the given data directory.
+    ///
+    /// The engine is recorded in the directory so that data written by one
+    /// engine is never opened by another; the marker is written before the
+    /// engine is opened, claiming the directory even if opening fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns `StoreError::EngineMismatch` if the directory holds data of a
+    /// different engine.
+    pub fn open(&self, dir: &Path) -> engine::Result<Box<dyn KvEngine>> {
+        let marker = dir.join(ENGINE_MARKER);
+        if marker.exists() {
+            let found = std::fs::read_to_string(&marker)?;
+            if found.trim() != self.to_string() {
+                return Err(StoreError::EngineMismatch {
+                    requested: self.to_string(),
+                    found: found.trim().to_owned(),
+                });
+            }
+        } else {
+            std::fs::write(&marker, self.to_string())?;
+        }
+
+        match self {
+            EngineType::Kvs => Ok(Box::new(KvStore::open(dir)?)),
+            EngineType::Sled => Ok(Box::new(SledKvEngine::open(dir)?)),
+        }
+    }
+}
+
 impl Display for EngineType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -59,3 +407,95 @@ impl Display for EngineType {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    // Opening a directory with the engine that wrote it should succeed.
+    #[test]
+    fn reopen_same_engine() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = EngineType::Kvs.open(temp_dir.path())?;
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        drop(engine);
+
+        let mut engine = EngineType::Kvs.open(temp_dir.path())?;
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    /// Engine that fails to write one key
+    struct FailingEngine {
+        store: KvStore,
+        failing_key: &'static str,
+    }
+
+    impl KvEngine for FailingEngine {
+        fn set(&mut self, key: String, value: String) -> engine::Result<()> {
+            if key == self.failing_key {
+                return Err(StoreError::Io(std::io::Error::other("injected failure")));
+            }
+            self.store.set(key, value)
+        }
+
+        fn get(&mut self, key: String) -> engine::Result<Option<String>> {
+            self.store.get(key)
+        }
+
+        fn remove(&mut self, key: String) -> engine::Result<()> {
+            self.store.remove(key)
+        }
+    }
+
+    // An engine error while applying a transaction should roll back the
+    // writes already applied.
+    #[test]
+    fn transaction_rollback() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut engine = FailingEngine {
+            store: KvStore::open(temp_dir.path())?,
+            failing_key: "key3",
+        };
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+
+        let ops = vec![
+            TransactionOp::Set {
+                key: "key1".to_owned(),
+                value: "value3".to_owned(),
+            },
+            TransactionOp::Rm {
+                key: "key2".to_owned(),
+            },
+            TransactionOp::Set {
+                key: "key4".to_owned(),
+                value: "value4".to_owned(),
+            },
+            TransactionOp::Set {
+                key: "key3".to_owned(),
+                value: "value3".to_owned(),
+            },
+        ];
+        assert!(apply_transaction(&mut engine, ops).is_err());
+        assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(engine.get("key3".to_owned())?, None);
+        assert_eq!(engine.get("key4".to_owned())?, None);
+        Ok(())
+    }
+
+    // Opening a directory with a different engine should fail.
+    #[test]
+    fn mismatched_engine() -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        drop(EngineType::Kvs.open(temp_dir.path())?);
+
+        assert!(matches!(
+            EngineType::Sled.open(temp_dir.path()),
+            Err(StoreError::EngineMismatch { .. })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+//! Counters describing the work done by a key-value server
+//!
+//! [`Metrics`] are rendered in the Prometheus text exposition format and
+//! served to clients sending a [`Request::Metrics`](crate::protocol::Request).
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    engine::EngineStats,
+    protocol::{Request, TransactionOp},
+};
+
+/// Counters of a key-value server
+///
+/// Request counters are updated as requests are handled; the storage engine's
+/// statistics are refreshed whenever the metrics are read through the server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    gets: AtomicU64,
+    sets: AtomicU64,
+    removes: AtomicU64,
+    compactions: AtomicU64,
+    reclaimed_space: AtomicU64,
+    live_keys: AtomicU64,
+}
+
+impl Metrics {
+    /// Returns the number of get requests handled.
+    pub fn gets(&self) -> u64 {
+        self.gets.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of set requests handled, including conditional
+    /// ones.
+    pub fn sets(&self) -> u64 {
+        self.sets.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of remove requests handled, including conditional
+    /// ones.
+    pub fn removes(&self) -> u64 {
+        self.removes.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of compactions run by the storage engine.
+    pub fn compactions(&self) -> u64 {
+        self.compactions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the space, in bytes, reclaimed by the storage engine's
+    /// compactions.
+    pub fn reclaimed_space(&self) -> u64 {
+        self.reclaimed_space.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of keys stored by the storage engine.
+    pub fn live_keys(&self) -> u64 {
+        self.live_keys.load(Ordering::Relaxed)
+    }
+
+    /// Counts a request about to be handled.
+    ///
+    /// Every operation of a transaction is counted on its own; checks count
+    /// as gets.
+    pub(crate) fn record(&self, request: &Request) {
+        let counter = match request {
+            Request::Get { .. } => &self.gets,
+            Request::Set { .. }
+            | Request::SetWithTtl { .. }
+            | Request::SetNx { .. }
+            | Request::Cas { .. } => &self.sets,
+            Request::Rm { .. } | Request::RmIfExists { .. } | Request::RemoveIf { .. } => {
+                &self.removes
+            }
+            Request::Metrics => return,
+            Request::Transaction { ops } => {
+                for op in ops {
+                    let counter = match op {
+                        TransactionOp::Get { .. } | TransactionOp::Check { .. } => &self.gets,
+                        TransactionOp::Set { .. } => &self.sets,
+                        TransactionOp::Rm { .. } => &self.removes,
+                    };
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+                return;
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Replaces the storage engine's statistics.
+    pub(crate) fn update_engine(&self, stats: EngineStats) {
+        self.compactions.store(stats.compactions, Ordering::Relaxed);
+        self.reclaimed_space
+            .store(stats.reclaimed_space, Ordering::Relaxed);
+        self.live_keys.store(stats.live_keys, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "kvs_gets_total",
+                "counter",
+                "Get requests handled",
+                self.gets(),
+            ),
+            (
+                "kvs_sets_total",
+                "counter",
+                "Set requests handled",
+                self.sets(),
+            ),
+            (
+                "kvs_removes_total",
+                "counter",
+                "Remove requests handled",
+                self.removes(),
+            ),
+            (
+                "kvs_compactions_total",
+                "counter",
+                "Compactions run by the storage engine",
+                self.compactions(),
+            ),
+            (
+                "kvs_reclaimed_bytes_total",
+                "counter",
+                "Bytes reclaimed by compactions",
+                self.reclaimed_space(),
+            ),
+            (
+                "kvs_live_keys",
+                "gauge",
+                "Keys currently stored",
+                self.live_keys(),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            // Writing to a string can not fail.
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Rendered metrics should follow the text exposition format.
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.record(&Request::Get {
+            key: "key1".to_owned(),
+        });
+        metrics.update_engine(EngineStats {
+            live_keys: 3,
+            compactions: 1,
+            reclaimed_space: 42,
+        });
+
+        let rendered = metrics.render();
+        assert!(rendered.starts_with(concat!(
+            "# HELP kvs_gets_total Get requests handled\n",
+            "# TYPE kvs_gets_total counter\n",
+            "kvs_gets_total 1\n",
+        )));
+        assert!(rendered.contains("\nkvs_sets_total 0\n"));
+        assert!(rendered.contains("\nkvs_reclaimed_bytes_total 42\n"));
+        assert!(rendered.contains("# TYPE kvs_live_keys gauge\nkvs_live_keys 3\n"));
+    }
+}
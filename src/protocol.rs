@@ -0,0 +1,166 @@
+//! Network protocol spoken between key-value clients and servers
+//!
+//! Clients send a stream of [`Request`]s over a TCP connection and the server
+//! answers every request with exactly one [`Response`], in order. Messages are
+//! serialized as JSON values written back to back on the stream.
+use std::io::{Read, Write};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::Result;
+
+/// A request sent from a client to the server.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Request {
+    /// Get the value of a key.
+    Get {
+        /// Key to look up
+        key: String,
+    },
+    /// Set the value of a key.
+    Set {
+        /// Key to set
+        key: String,
+        /// Value to store
+        value: String,
+    },
+    /// Remove a key.
+    Rm {
+        /// Key to remove
+        key: String,
+    },
+    /// Remove a key, treating a missing key as already removed.
+    ///
+    /// Answered with a [`Response::Success`] telling whether the key existed.
+    RmIfExists {
+        /// Key to remove
+        key: String,
+    },
+    /// Set the value of a key that expires after `ttl_ms` milliseconds.
+    SetWithTtl {
+        /// Key to set
+        key: String,
+        /// Value to store
+        value: String,
+        /// Time to live of the key, in milliseconds
+        ttl_ms: u64,
+    },
+    /// Set the value of a key only if the key does not exist.
+    SetNx {
+        /// Key to set
+        key: String,
+        /// Value to store
+        value: String,
+    },
+    /// Replace the value of a key only if it currently holds `expected`.
+    Cas {
+        /// Key to set
+        key: String,
+        /// Value the key must currently hold
+        expected: String,
+        /// Value to store
+        new: String,
+    },
+    /// Remove a key only if it currently holds `expected`.
+    RemoveIf {
+        /// Key to remove
+        key: String,
+        /// Value the key must currently hold
+        expected: String,
+    },
+    /// Read the server's metrics.
+    ///
+    /// Answered with a [`Response::Value`] holding the metrics in the
+    /// Prometheus text exposition format.
+    Metrics,
+    /// Apply a sequence of operations atomically.
+    ///
+    /// Operations run in order, each seeing the writes of the ones before it.
+    /// The writes are applied only if every operation succeeds; no other
+    /// request observes the transaction partially applied. Should the engine
+    /// fail while applying them, the writes already applied are rolled back
+    /// and the error is answered instead. Answered with a
+    /// [`Response::Transaction`].
+    Transaction {
+        /// Operations to apply, in order
+        ops: Vec<TransactionOp>,
+    },
+}
+
+/// An operation of a [`Request::Transaction`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TransactionOp {
+    /// Get the value of a key; results in a [`Response::Value`].
+    Get {
+        /// Key to look up
+        key: String,
+    },
+    /// Set the value of a key; results in [`Response::Ok`].
+    Set {
+        /// Key to set
+        key: String,
+        /// Value to store
+        value: String,
+    },
+    /// Remove a key; results in [`Response::Ok`], or fails the transaction
+    /// with a [`Response::Err`] if the key does not exist.
+    Rm {
+        /// Key to remove
+        key: String,
+    },
+    /// Check that a key holds `expected`, or does not exist if `None`;
+    /// results in a [`Response::Success`] and fails the transaction if the
+    /// check does not hold.
+    Check {
+        /// Key to check
+        key: String,
+        /// Value the key must currently hold
+        expected: Option<String>,
+    },
+}
+
+/// A response sent from the server to a client.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Response {
+    /// The request succeeded without returning a value.
+    Ok,
+    /// The value of a key; `None` if the key does not exist.
+    Value(Option<String>),
+    /// Whether a conditional request was applied.
+    Success(bool),
+    /// The storage engine does not support the request; contains the name of
+    /// the missing [`EngineCapabilities`](crate::engine::EngineCapabilities)
+    /// flag.
+    Unsupported(String),
+    /// The request failed; contains a description of the error.
+    Err(String),
+    /// The outcome of a [`Request::Transaction`].
+    Transaction {
+        /// Whether the transaction's writes were applied
+        committed: bool,
+        /// Result of every operation, in order; a failed transaction ends
+        /// with the result of the operation that failed it
+        results: Vec<Response>,
+    },
+}
+
+/// Writes a protocol message to the stream and flushes it.
+///
+/// The message is written with a single write, so an unbuffered socket does
+/// not send it in many small packets.
+pub fn send<T: Serialize>(mut writer: impl Write, message: &T) -> Result<()> {
+    writer.write_all(&serde_json::to_vec(message)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next protocol message from the stream.
+///
+/// Returns `None` if the stream was closed before a message started.
+pub fn receive<T: DeserializeOwned>(reader: impl Read) -> Result<Option<T>> {
+    serde_json::Deserializer::from_reader(reader)
+        .into_iter()
+        .next()
+        .transpose()
+        .map_err(Into::into)
+}
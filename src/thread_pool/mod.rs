@@ -0,0 +1,26 @@
+//! Thread pools used to serve connections concurrently
+//!
+mod shared_queue;
+
+pub use shared_queue::SharedQueueThreadPool;
+
+use crate::Result;
+
+/// A pool of threads running submitted jobs.
+pub trait ThreadPool {
+    /// Creates a pool running jobs on `threads` threads.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a thread could not be spawned.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs a job on one of the pool's threads.
+    ///
+    /// A panicking job does not reduce the number of threads in the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,129 @@
+//! Thread pool whose threads share a single job queue
+//!
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+};
+
+use tracing::error;
+
+use super::ThreadPool;
+use crate::Result;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Thread pool whose threads take jobs from a shared queue.
+///
+/// Idle threads block on the queue, so a job runs as soon as any thread is
+/// free. A thread whose job panics is replaced by a new one. Dropping the pool
+/// lets the threads finish the queued jobs and exit.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = JobReceiver(Arc::new(Mutex::new(receiver)));
+        for _ in 0..threads {
+            spawn_worker(receiver.clone())?;
+        }
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("thread pool has no threads left");
+    }
+}
+
+/// Handle to the shared job queue held by every worker thread.
+///
+/// Replaces its worker with a new thread when dropped by a panicking job.
+#[derive(Clone)]
+struct JobReceiver(Arc<Mutex<Receiver<Job>>>);
+
+impl Drop for JobReceiver {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            if let Err(err) = spawn_worker(self.clone()) {
+                error!(target: "thread_pool", error = %err, "unable to replace panicked thread");
+            }
+        }
+    }
+}
+
+/// Spawns a worker thread running jobs from the queue until the pool is
+/// dropped.
+fn spawn_worker(receiver: JobReceiver) -> Result<()> {
+    thread::Builder::new().spawn(move || loop {
+        // The lock is released before the job runs, so a panicking job can
+        // not poison it.
+        let job = receiver
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => break,
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::time::Duration;
+
+    // Every spawned job should run.
+    #[test]
+    fn runs_all_jobs() -> Result<()> {
+        let pool = SharedQueueThreadPool::new(4)?;
+        let (sender, receiver) = mpsc::channel();
+        for id in 0..100 {
+            let sender = sender.clone();
+            pool.spawn(move || sender.send(id).unwrap());
+        }
+
+        let mut ids: Vec<i32> = (0..100)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..100).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    // Panicking jobs should not take threads out of the pool.
+    #[test]
+    fn survives_panicking_jobs() -> Result<()> {
+        let pool = SharedQueueThreadPool::new(2)?;
+        for _ in 0..4 {
+            pool.spawn(|| panic!("job panicked"));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        for _ in 0..2 {
+            let sender = sender.clone();
+            pool.spawn(move || sender.send(()).unwrap());
+        }
+        drop(sender);
+        for _ in 0..2 {
+            receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(100)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+        Ok(())
+    }
+}
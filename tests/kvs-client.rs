@@ -1,6 +1,6 @@
 use assert_cmd::prelude::*;
-use predicates::str::contains;
-use std::process::Command;
+use predicates::str::{contains, is_empty};
+use std::{process::Command, thread, time::Duration};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -114,6 +114,46 @@ fn client_cli_invalid_rm() {
         .failure();
 }
 
+// `kvs-client rm --if-exists` should succeed whether or not the key exists,
+// while a plain `rm` of a missing key fails.
+#[test]
+fn client_cli_rm_if_exists() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    for _ in 0..2 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["rm", "key1", "--if-exists", "--addr", "127.0.0.1:4011"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
 #[test]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
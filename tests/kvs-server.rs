@@ -202,3 +202,168 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Environment variables should configure the server and client when flags
+// are omitted.
+#[test]
+fn cli_env_configuration() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .env("KVS_ADDR", "127.0.0.1:4006")
+        .env("KVS_ENGINE", "sled")
+        .env("KVS_DATA_DIR", data_dir.path())
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .env("KVS_ADDR", "127.0.0.1:4006")
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("127.0.0.1:4006"));
+    assert!(content.contains("sled"));
+    assert_eq!(
+        fs::read_to_string(data_dir.path().join("engine")).unwrap(),
+        "sled"
+    );
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// Flags should take precedence over environment variables.
+#[test]
+fn cli_flags_override_env() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = TempDir::new().unwrap();
+    let env_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4008", "--data-dir"])
+        .arg(data_dir.path())
+        .env("KVS_ADDR", "127.0.0.1:4007")
+        .env("KVS_ENGINE", "sled")
+        .env("KVS_DATA_DIR", env_dir.path())
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4008"])
+        .env("KVS_ADDR", "127.0.0.1:4007")
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("127.0.0.1:4008"));
+    assert_eq!(
+        fs::read_to_string(data_dir.path().join("engine")).unwrap(),
+        "kvs"
+    );
+    assert!(!env_dir.path().join("engine").exists());
+}
+
+// `get --raw` should write stored values byte for byte, and nothing for a
+// missing key.
+#[test]
+fn cli_get_raw() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for value in ["value1", "line1\nline2", "trailing\n\n"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", value, "--addr", "127.0.0.1:4009"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        let output_path = temp_dir.path().join("output");
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--raw", "--addr", "127.0.0.1:4009"])
+            .current_dir(&temp_dir)
+            .stdout(File::create(&output_path).unwrap())
+            .assert()
+            .success();
+        assert_eq!(fs::read(&output_path).unwrap(), value.as_bytes());
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--raw", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty());
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// Without a configured data directory, the server should explain an
+// unwritable current directory, and store data under XDG_DATA_HOME when set.
+#[cfg(unix)]
+#[test]
+fn cli_data_dir_fallback() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let data_home = TempDir::new().unwrap();
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o555)).unwrap();
+
+    // Permissions are not enforced for privileged users.
+    if File::create(temp_dir.path().join("probe")).is_err() {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:4010"])
+            .env_remove("KVS_DATA_DIR")
+            .env_remove("XDG_DATA_HOME")
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("is not writable"))
+            .stderr(contains("--data-dir"));
+    }
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4010"])
+        .env_remove("KVS_DATA_DIR")
+        .env("XDG_DATA_HOME", data_home.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    fs::set_permissions(temp_dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+    assert_eq!(
+        fs::read_to_string(data_home.path().join("kvs").join("engine")).unwrap(),
+        "kvs"
+    );
+    assert!(!temp_dir.path().join("engine").exists());
+}
@@ -0,0 +1,618 @@
+use kvs::engine::{EngineCapabilities, KvEngine, KvStore, SledKvEngine};
+use kvs::protocol::{self, Request, Response, TransactionOp};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvServer, KvsClient, KvsError};
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use tempfile::TempDir;
+
+// Requests sent over a single connection should be answered in order.
+#[test]
+fn protocol_round_trip() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        Box::new(KvStore::open(temp_dir.path()).unwrap()),
+        SharedQueueThreadPool::new(1).unwrap(),
+    );
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_connection(stream).unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = |request: Request| -> Response {
+        protocol::send(&stream, &request).unwrap();
+        protocol::receive(&mut reader).unwrap().unwrap()
+    };
+
+    assert_eq!(
+        request(Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        }),
+        Response::Ok
+    );
+    assert_eq!(
+        request(Request::Get {
+            key: "key1".to_owned()
+        }),
+        Response::Value(Some("value1".to_owned()))
+    );
+    assert_eq!(
+        request(Request::Get {
+            key: "key2".to_owned()
+        }),
+        Response::Value(None)
+    );
+    assert_eq!(
+        request(Request::Rm {
+            key: "key2".to_owned()
+        }),
+        Response::Err("Key not found".to_owned())
+    );
+    assert_eq!(
+        request(Request::Rm {
+            key: "key1".to_owned()
+        }),
+        Response::Ok
+    );
+    assert_eq!(
+        request(Request::Get {
+            key: "key1".to_owned()
+        }),
+        Response::Value(None)
+    );
+    assert_eq!(
+        request(Request::RmIfExists {
+            key: "key1".to_owned()
+        }),
+        Response::Success(false)
+    );
+
+    // Closing the connection should end the server's handling of it.
+    drop(reader);
+    drop(stream);
+    handle.join().unwrap();
+}
+
+// Concurrent clients should all be served.
+#[test]
+fn concurrent_clients() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        Box::new(KvStore::open(temp_dir.path()).unwrap()),
+        SharedQueueThreadPool::new(4).unwrap(),
+    );
+    let (_shutdown, signal) = mpsc::channel();
+    thread::spawn(move || server.run(listener, signal));
+
+    let clients: Vec<_> = (0..16)
+        .map(|id| {
+            thread::spawn(move || {
+                let stream = TcpStream::connect(addr).unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                for round in 0..10 {
+                    let key = format!("key{}", id);
+                    let value = format!("value{}-{}", id, round);
+                    protocol::send(
+                        &stream,
+                        &Request::Set {
+                            key: key.clone(),
+                            value: value.clone(),
+                        },
+                    )
+                    .unwrap();
+                    let response: Response = protocol::receive(&mut reader).unwrap().unwrap();
+                    assert_eq!(response, Response::Ok);
+
+                    protocol::send(&stream, &Request::Get { key }).unwrap();
+                    let response: Response = protocol::receive(&mut reader).unwrap().unwrap();
+                    assert_eq!(response, Response::Value(Some(value)));
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+// Signalling shutdown should close open connections, make `run` return and
+// leave every acknowledged write on disk.
+#[test]
+fn graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        Box::new(KvStore::open(temp_dir.path()).unwrap()),
+        SharedQueueThreadPool::new(2).unwrap(),
+    );
+    let (shutdown, signal) = mpsc::channel();
+    let handle = thread::spawn(move || server.run(listener, signal));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    protocol::send(
+        &stream,
+        &Request::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    )
+    .unwrap();
+    let response: Response = protocol::receive(&mut reader).unwrap().unwrap();
+    assert_eq!(response, Response::Ok);
+
+    // The connection is left open; shutting down must not wait for the client
+    // to close it.
+    shutdown.send(()).unwrap();
+    handle.join().unwrap().unwrap();
+    assert!(protocol::receive::<Response>(&mut reader)
+        .unwrap()
+        .is_none());
+    assert!(TcpStream::connect(addr).is_err());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// Handled requests and the engine's compactions should advance the metrics.
+#[test]
+fn metrics() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let store = KvStore::builder()
+        .compaction_threshold(100)
+        .open(temp_dir.path())
+        .unwrap();
+    let server = KvServer::new(Box::new(store), SharedQueueThreadPool::new(1).unwrap());
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_connection(stream).unwrap();
+        server
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = |request: Request| -> Response {
+        protocol::send(&stream, &request).unwrap();
+        protocol::receive(&mut reader).unwrap().unwrap()
+    };
+    for i in 0..10 {
+        request(Request::Set {
+            key: format!("key{}", i % 3),
+            value: format!("value{}", i),
+        });
+    }
+    request(Request::Get {
+        key: "key1".to_owned(),
+    });
+    request(Request::Rm {
+        key: "key0".to_owned(),
+    });
+    request(Request::Rm {
+        key: "missing".to_owned(),
+    });
+
+    let Response::Value(Some(exposition)) = request(Request::Metrics) else {
+        panic!("metrics request failed");
+    };
+    assert!(exposition.contains("\nkvs_gets_total 1\n"));
+    assert!(exposition.contains("\nkvs_sets_total 10\n"));
+    assert!(exposition.contains("\nkvs_removes_total 2\n"));
+    assert!(exposition.contains("\nkvs_live_keys 2\n"));
+
+    drop(reader);
+    drop(stream);
+    let server = handle.join().unwrap();
+    let metrics = server.metrics();
+    assert_eq!(metrics.gets(), 1);
+    assert_eq!(metrics.sets(), 10);
+    assert_eq!(metrics.removes(), 2);
+    assert_eq!(metrics.live_keys(), 2);
+    assert!(metrics.compactions() > 0);
+    assert!(metrics.reclaimed_space() > 0);
+}
+
+// Conditional requests should only apply when the key's current value
+// matches.
+#[test]
+fn conditional_requests() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        Box::new(KvStore::open(temp_dir.path()).unwrap()),
+        SharedQueueThreadPool::new(1).unwrap(),
+    );
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_connection(stream).unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = |request: Request| -> Response {
+        protocol::send(&stream, &request).unwrap();
+        protocol::receive(&mut reader).unwrap().unwrap()
+    };
+    let cas = |key: &str, expected: &str, new: &str| Request::Cas {
+        key: key.to_owned(),
+        expected: expected.to_owned(),
+        new: new.to_owned(),
+    };
+    let remove_if = |key: &str, expected: &str| Request::RemoveIf {
+        key: key.to_owned(),
+        expected: expected.to_owned(),
+    };
+    let get = |key: &str| Request::Get {
+        key: key.to_owned(),
+    };
+
+    // Only the first of two clients racing to create a key wins.
+    for (value, applied) in [("owner1", true), ("owner2", false)] {
+        assert_eq!(
+            request(Request::SetNx {
+                key: "lock".to_owned(),
+                value: value.to_owned(),
+            }),
+            Response::Success(applied)
+        );
+    }
+    assert_eq!(
+        request(get("lock")),
+        Response::Value(Some("owner1".to_owned()))
+    );
+
+    // A swap needs the current value; a missing key is never created.
+    assert_eq!(
+        request(cas("lock", "owner2", "owner3")),
+        Response::Success(false)
+    );
+    assert_eq!(
+        request(cas("lock", "owner1", "owner3")),
+        Response::Success(true)
+    );
+    assert_eq!(
+        request(cas("missing", "owner1", "owner3")),
+        Response::Success(false)
+    );
+    assert_eq!(request(get("missing")), Response::Value(None));
+
+    // Only the current holder can release the lock, after which it can be
+    // created again.
+    assert_eq!(
+        request(remove_if("lock", "owner1")),
+        Response::Success(false)
+    );
+    assert_eq!(
+        request(remove_if("lock", "owner3")),
+        Response::Success(true)
+    );
+    assert_eq!(
+        request(remove_if("lock", "owner3")),
+        Response::Success(false)
+    );
+    assert_eq!(request(get("lock")), Response::Value(None));
+    assert_eq!(
+        request(Request::SetNx {
+            key: "lock".to_owned(),
+            value: "owner2".to_owned(),
+        }),
+        Response::Success(true)
+    );
+
+    drop(reader);
+    drop(stream);
+    handle.join().unwrap();
+}
+
+// Engines should report what they support, and the server should reject
+// requests the engine can not serve.
+#[test]
+fn capabilities() {
+    let kvs_dir = TempDir::new().unwrap();
+    let sled_dir = TempDir::new().unwrap();
+    let store = KvStore::open(kvs_dir.path()).unwrap();
+    let sled = SledKvEngine::open(sled_dir.path()).unwrap();
+    assert_eq!(
+        store.capabilities(),
+        EngineCapabilities {
+            scan: true,
+            ttl: true,
+            cas: true,
+            compaction: true,
+            batch: true,
+        }
+    );
+    assert_eq!(
+        sled.capabilities(),
+        EngineCapabilities {
+            scan: true,
+            cas: true,
+            ..Default::default()
+        }
+    );
+
+    let engines: [Box<dyn KvEngine>; 2] = [Box::new(store), Box::new(sled)];
+    let expected = [Response::Ok, Response::Unsupported("ttl".to_owned())];
+    for (engine, expected) in engines.into_iter().zip(expected) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = KvServer::new(engine, SharedQueueThreadPool::new(1).unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            server.handle_connection(stream).unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request = |request: Request| -> Response {
+            protocol::send(&stream, &request).unwrap();
+            protocol::receive(&mut reader).unwrap().unwrap()
+        };
+        assert_eq!(
+            request(Request::SetWithTtl {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+                ttl_ms: 60_000,
+            }),
+            expected
+        );
+        // The connection stays usable after a rejected request.
+        assert_eq!(
+            request(Request::Set {
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+            }),
+            Response::Ok
+        );
+
+        drop(reader);
+        drop(stream);
+        handle.join().unwrap();
+    }
+}
+
+// Transactions should apply their writes only if every operation succeeds.
+#[test]
+fn transactions() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        Box::new(KvStore::open(temp_dir.path()).unwrap()),
+        SharedQueueThreadPool::new(1).unwrap(),
+    );
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_connection(stream).unwrap();
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = |request: Request| -> Response {
+        protocol::send(&stream, &request).unwrap();
+        protocol::receive(&mut reader).unwrap().unwrap()
+    };
+    // Moves the value of `a` to `b`, if `a` holds `expected`.
+    let move_value = |expected: &str| Request::Transaction {
+        ops: vec![
+            TransactionOp::Get {
+                key: "a".to_owned(),
+            },
+            TransactionOp::Check {
+                key: "a".to_owned(),
+                expected: Some(expected.to_owned()),
+            },
+            TransactionOp::Set {
+                key: "b".to_owned(),
+                value: expected.to_owned(),
+            },
+            TransactionOp::Rm {
+                key: "a".to_owned(),
+            },
+            TransactionOp::Get {
+                key: "a".to_owned(),
+            },
+        ],
+    };
+    let get = |key: &str| Request::Get {
+        key: key.to_owned(),
+    };
+
+    assert_eq!(
+        request(Request::Set {
+            key: "a".to_owned(),
+            value: "value1".to_owned(),
+        }),
+        Response::Ok
+    );
+    assert_eq!(
+        request(move_value("value2")),
+        Response::Transaction {
+            committed: false,
+            results: vec![
+                Response::Value(Some("value1".to_owned())),
+                Response::Success(false),
+            ],
+        }
+    );
+    assert_eq!(request(get("b")), Response::Value(None));
+
+    assert_eq!(
+        request(move_value("value1")),
+        Response::Transaction {
+            committed: true,
+            results: vec![
+                Response::Value(Some("value1".to_owned())),
+                Response::Success(true),
+                Response::Ok,
+                Response::Ok,
+                Response::Value(None),
+            ],
+        }
+    );
+    assert_eq!(request(get("a")), Response::Value(None));
+    assert_eq!(
+        request(get("b")),
+        Response::Value(Some("value1".to_owned()))
+    );
+
+    // A failing operation discards the writes before it.
+    assert_eq!(
+        request(Request::Transaction {
+            ops: vec![
+                TransactionOp::Set {
+                    key: "c".to_owned(),
+                    value: "value3".to_owned(),
+                },
+                TransactionOp::Rm {
+                    key: "a".to_owned(),
+                },
+            ],
+        }),
+        Response::Transaction {
+            committed: false,
+            results: vec![Response::Ok, Response::Err("Key not found".to_owned())],
+        }
+    );
+    assert_eq!(request(get("c")), Response::Value(None));
+
+    drop(reader);
+    drop(stream);
+    handle.join().unwrap();
+}
+
+// A client should send all of its requests over one connection; the server
+// below accepts only one.
+#[test]
+fn client_reuses_connection() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        Box::new(KvStore::open(temp_dir.path()).unwrap()),
+        SharedQueueThreadPool::new(1).unwrap(),
+    );
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_connection(stream).unwrap();
+    });
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    client.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    client.remove("key1".to_owned()).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::Server(err)) if err == "Key not found"
+    ));
+    assert!(!client
+        .set_nx("key2".to_owned(), "other".to_owned())
+        .unwrap());
+    assert!(client
+        .compare_and_swap("key2".to_owned(), "value2".to_owned(), "value3".to_owned())
+        .unwrap());
+    assert!(client
+        .remove_if("key2".to_owned(), "value3".to_owned())
+        .unwrap());
+    assert_eq!(client.get("key2".to_owned()).unwrap(), None);
+
+    drop(client);
+    handle.join().unwrap();
+}
+
+// A client should reconnect and resend its request when the server drops the
+// connection between requests.
+#[test]
+fn client_reconnects() {
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = KvServer::new(
+        Box::new(KvStore::open(temp_dir.path()).unwrap()),
+        SharedQueueThreadPool::new(1).unwrap(),
+    );
+    let handle = thread::spawn(move || {
+        // Answer a single request, then hang up.
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let request: Request = protocol::receive(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            request,
+            Request::Get {
+                key: "key1".to_owned()
+            }
+        );
+        protocol::send(&stream, &Response::Value(None)).unwrap();
+        drop((stream, reader));
+
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_connection(stream).unwrap();
+    });
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+
+    drop(client);
+    handle.join().unwrap();
+}
+
+// A client should not resend a request the server may already have applied,
+// unless applying it twice is harmless.
+#[test]
+fn client_does_not_resend_conditional_writes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = thread::spawn(move || {
+        // Hang up on the first request without answering it.
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let request: Request = protocol::receive(&mut reader).unwrap().unwrap();
+        assert!(matches!(request, Request::SetNx { .. }));
+        drop((stream, reader));
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let request: Request = protocol::receive(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            request,
+            Request::Get {
+                key: "key1".to_owned()
+            }
+        );
+        protocol::send(&stream, &Response::Value(None)).unwrap();
+    });
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert!(client
+        .set_nx("key1".to_owned(), "value1".to_owned())
+        .is_err());
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+
+    drop(client);
+    handle.join().unwrap();
+}